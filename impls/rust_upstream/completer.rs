@@ -0,0 +1,236 @@
+use std::borrow::Cow;
+use std::fs;
+
+use regex::Regex;
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::env::{env_find, env_get, env_names, Env};
+use crate::types::MalVal::{self, Func, Hash, Int, MalFunc, Nil, Str, Sym, Vector};
+
+const SPECIAL_FORMS: &[&str] = &[
+    "catch*",
+    "def!",
+    "defmacro!",
+    "do",
+    "eval",
+    "fn*",
+    "handler-bind",
+    "if",
+    "lazy-seq",
+    "let*",
+    "loop*",
+    "macroexpand",
+    "quasiquote",
+    "quasiquoteexpand",
+    "quote",
+    "recur",
+    "restart-case",
+    "try*",
+];
+
+// Characters that end a symbol.
+const DELIMITERS: &str = " \t\n()[]{}'\"`,;@~^";
+
+// Tab completes the symbols bound in env and the special forms, and file
+// paths inside the string given to load-file. Typing the name of a
+// function right after an opening paren hints at its parameters, and its
+// :doc if its meta has one.
+pub struct MalHelper {
+    env: Env,
+}
+
+impl MalHelper {
+    pub fn new(env: Env) -> MalHelper {
+        MalHelper { env }
+    }
+}
+
+fn word_start(before: &str) -> usize {
+    before
+        .rfind(|c| DELIMITERS.contains(c))
+        .map_or(0, |i| i + 1)
+}
+
+// Whether before ends inside a string: one is left open once complete
+// strings and comments are removed.
+fn in_string(before: &str) -> bool {
+    lazy_static! {
+        static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*"|;.*"#).unwrap();
+    }
+    STR_RE.replace_all(before, "").contains('"')
+}
+
+fn complete_path(partial: &str) -> Vec<Pair> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => (&partial[..i + 1], &partial[i + 1..]),
+        None => ("", partial),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            Some(match e.file_type() {
+                Ok(t) if t.is_dir() => format!("{}/", name),
+                _ => name,
+            })
+        })
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|n| Pair {
+            display: n.clone(),
+            replacement: n,
+        })
+        .collect()
+}
+
+// The parameters of a builtin from the signature in its meta (see
+// core::ns): required ones by type, optional ones in brackets and any
+// further ones after &.
+fn builtin_hint(meta: &MalVal) -> Option<String> {
+    let get = |k: &str| match meta {
+        Hash(hm, _) => hm.get(&Str(format!("\u{29e}{}", k))).cloned(),
+        _ => None,
+    };
+    let (min, max, params) = match (get("min-args")?, get("max-args")?, get("params")?) {
+        (Int(min), max, Vector(params, _)) => (min as usize, max, params),
+        _ => return None,
+    };
+    let types = |i: usize| match params.get(i.min(params.len().saturating_sub(1))) {
+        Some(Vector(tys, _)) => tys
+            .iter()
+            .map(|t| t.pr_str(false)[1..].to_string())
+            .collect::<Vec<_>>()
+            .join("|"),
+        _ => "any".to_string(),
+    };
+    let mut parts = vec![];
+    match max {
+        Int(max) => {
+            for i in 0..max as usize {
+                parts.push(if i < min {
+                    types(i)
+                } else {
+                    format!("[{}]", types(i))
+                });
+            }
+        }
+        _ => {
+            for i in 0..min {
+                parts.push(types(i));
+            }
+            parts.push(format!("& {}", types(min)));
+        }
+    }
+    Some(parts.join(" "))
+}
+
+fn fn_hint(f: &MalVal) -> Option<String> {
+    let (params, meta) = match f {
+        Func(_, meta) => (builtin_hint(meta)?, meta),
+        MalFunc {
+            params, ast, meta, ..
+        } => match **params {
+            Nil => (
+                ast.seq_iter()?
+                    .filter_map(|c| c.seq_iter()?.next().map(|p| p.pr_str(true)))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                meta,
+            ),
+            ref p => (p.pr_str(true), meta),
+        },
+        _ => return None,
+    };
+    let doc = match **meta {
+        Hash(ref hm, _) => match hm.get(&Str("\u{29e}doc".to_string())) {
+            Some(Str(doc)) => doc.lines().next().map(|l| format!("  ; {}", l)),
+            _ => None,
+        },
+        _ => None,
+    };
+    Some(format!(" {}{}", params, doc.unwrap_or_default()))
+}
+
+impl Completer for MalHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        lazy_static! {
+            static ref LOAD_FILE_RE: Regex = Regex::new(r#"\(load-file\s+"([^"]*)$"#).unwrap();
+        }
+        let before = &line[..pos];
+        if let Some(caps) = LOAD_FILE_RE.captures(before) {
+            let partial = caps.get(1).unwrap();
+            let start = partial.start() + partial.as_str().rfind('/').map_or(0, |i| i + 1);
+            return Ok((start, complete_path(partial.as_str())));
+        }
+        let start = word_start(before);
+        let prefix = &before[start..];
+        if prefix.is_empty() || in_string(before) {
+            return Ok((pos, vec![]));
+        }
+        let mut names: Vec<String> = SPECIAL_FORMS
+            .iter()
+            .map(|s| s.to_string())
+            .chain(env_names(&self.env))
+            .filter(|n| n.starts_with(prefix))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok((
+            start,
+            names
+                .into_iter()
+                .map(|n| Pair {
+                    display: n.clone(),
+                    replacement: n,
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for MalHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let start = word_start(line);
+        let name = &line[start..];
+        if name.is_empty() || !line[..start].ends_with('(') || in_string(line) {
+            return None;
+        }
+        let env = env_find(&self.env, name)?;
+        fn_hint(&env_get(&env, &Sym(name.to_string())).ok()?)
+    }
+}
+
+impl Highlighter for MalHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[90m{}\x1b[0m", hint))
+    }
+}
+
+impl Validator for MalHelper {}
+
+impl Helper for MalHelper {}
@@ -0,0 +1,187 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::types::MalErr::{ErrRestart, ErrString};
+use crate::types::MalVal::{List, Nil, Str, Sym};
+use crate::types::{error, MalArgs, MalErr, MalRet, MalVal};
+
+// Dynamic state of the condition system. Handlers and restarts are
+// established for the extent of a form and searched innermost first, so
+// both are kept as stacks. CATCHES records the handler depth at each
+// enclosing try* with a catch* clause.
+
+struct Restart {
+    name: String,
+    id: usize,
+}
+
+thread_local! {
+    static HANDLERS: RefCell<Vec<MalVal>> = const { RefCell::new(vec![]) };
+    static RESTARTS: RefCell<Vec<Restart>> = const { RefCell::new(vec![]) };
+    static CATCHES: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+pub enum Outcome {
+    Done(MalVal),
+    Restart(usize, MalArgs),
+}
+
+// Truncates a stack back to its depth on entry when dropped, so handlers
+// and restarts are popped even if a panic unwinds through their form.
+struct HandlersGuard(usize);
+struct RestartsGuard(usize);
+struct CatchesGuard(usize);
+
+impl Drop for HandlersGuard {
+    fn drop(&mut self) {
+        HANDLERS.with(|h| h.borrow_mut().truncate(self.0));
+    }
+}
+
+impl Drop for RestartsGuard {
+    fn drop(&mut self) {
+        RESTARTS.with(|r| r.borrow_mut().truncate(self.0));
+    }
+}
+
+impl Drop for CatchesGuard {
+    fn drop(&mut self) {
+        CATCHES.with(|c| c.borrow_mut().truncate(self.0));
+    }
+}
+
+// Puts back the handlers hidden from a running handler when dropped.
+struct HiddenGuard(Vec<MalVal>);
+
+impl Drop for HiddenGuard {
+    fn drop(&mut self) {
+        let hidden = self.0.drain(..);
+        HANDLERS.with(|h| h.borrow_mut().extend(hidden));
+    }
+}
+
+pub fn with_handler<F>(handler: MalVal, body: F) -> MalRet
+where
+    F: FnOnce() -> MalRet,
+{
+    let _guard = HandlersGuard(HANDLERS.with(|h| {
+        let mut h = h.borrow_mut();
+        h.push(handler);
+        h.len() - 1
+    }));
+    body()
+}
+
+// Evaluate body as the body of a try* that catches errors. An error
+// raised inside it is offered only to the handlers established inside it,
+// since the catch* stops it before any outer handler could take over.
+pub fn with_catch<F>(body: F) -> MalRet
+where
+    F: FnOnce() -> MalRet,
+{
+    let depth = HANDLERS.with(|h| h.borrow().len());
+    let _guard = CatchesGuard(CATCHES.with(|c| {
+        let mut c = c.borrow_mut();
+        c.push(depth);
+        c.len() - 1
+    }));
+    body()
+}
+
+// Call each active handler with cond, innermost first, without unwinding.
+// A handler declines by returning normally; it takes over by invoking a
+// restart (or throwing), which unwinds through here as an Err.
+pub fn signal(cond: &MalVal) -> MalRet {
+    signal_from(cond, 0)
+}
+
+// Like signal, for a condition that is about to unwind as an error: the
+// search stops at the innermost try* that will catch it.
+pub fn signal_error(cond: &MalVal) -> MalRet {
+    let floor = CATCHES.with(|c| c.borrow().last().cloned().unwrap_or(0));
+    signal_from(cond, floor)
+}
+
+fn signal_from(cond: &MalVal, floor: usize) -> MalRet {
+    let handlers = HANDLERS.with(|h| h.borrow().clone());
+    for (i, h) in handlers.iter().enumerate().skip(floor).rev() {
+        // a handler runs with only the handlers outside of it in effect
+        let _guard = HiddenGuard(HANDLERS.with(|hs| hs.borrow_mut().split_off(i)));
+        h.apply(vec![cond.clone()])?;
+    }
+    Ok(Nil)
+}
+
+// Evaluate body with the named restarts established. When one of them is
+// invoked, body is unwound and its index in names is returned with the
+// arguments passed to invoke-restart.
+pub fn restart_case<F>(names: &[String], body: F) -> Result<Outcome, MalErr>
+where
+    F: FnOnce() -> MalRet,
+{
+    let base = NEXT_ID.with(|n| {
+        let base = n.get();
+        n.set(base + names.len());
+        base
+    });
+    let guard = RestartsGuard(RESTARTS.with(|r| {
+        let mut r = r.borrow_mut();
+        let depth = r.len();
+        // pushed in reverse so the first clause is found first
+        for (i, name) in names.iter().enumerate().rev() {
+            r.push(Restart {
+                name: name.to_string(),
+                id: base + i,
+            });
+        }
+        depth
+    }));
+    let res = body();
+    drop(guard);
+    match res {
+        Ok(v) => Ok(Outcome::Done(v)),
+        Err(ErrRestart(id, args)) if id >= base && id < base + names.len() => {
+            Ok(Outcome::Restart(id - base, args))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn restart_name(mv: &MalVal) -> Result<String, MalErr> {
+    match mv {
+        Sym(s) => Ok(s.to_string()),
+        Str(s) if !mv.keyword_q() => Ok(s.to_string()),
+        _ => Err(ErrString(format!(
+            "restart name must be a symbol, got {}",
+            mv.pr_str(true)
+        ))),
+    }
+}
+
+pub fn invoke_restart(a: MalArgs) -> MalRet {
+    if a.is_empty() {
+        return error("invoke-restart: missing restart name");
+    }
+    let name = restart_name(&a[0])?;
+    let id = RESTARTS.with(|r| {
+        r.borrow()
+            .iter()
+            .rev()
+            .find(|r| r.name == name)
+            .map(|r| r.id)
+    });
+    match id {
+        Some(id) => Err(ErrRestart(id, a[1..].to_vec())),
+        None => error(&format!("no restart named '{}' is active", name)),
+    }
+}
+
+pub fn compute_restarts(_a: MalArgs) -> MalRet {
+    Ok(list!(RESTARTS.with(|r| r
+        .borrow()
+        .iter()
+        .rev()
+        .map(|r| Sym(r.name.to_string()))
+        .collect::<MalArgs>())))
+}
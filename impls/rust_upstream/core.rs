@@ -1,24 +1,58 @@
-use std::fs::File;
-use std::io::Read;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash as StdHash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::process::{self, Command, Stdio};
 use std::rc::Rc;
 use std::sync::Mutex;
+use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
+use fnv::FnvHasher;
+use im_rc::Vector as ImVector;
+use itertools::Itertools;
+use regex::Captures;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use unicode_segmentation::UnicodeSegmentation;
 
+use crate::condition::{compute_restarts, invoke_restart, signal, signal_error};
 use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use crate::reader::{read_edn, read_str, register_tag};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, LazySeq, List, MalFunc, Nil, Reader, Regex, Set, SortedMap,
+    SortedSet, Str, Sym, Vector,
+};
+use crate::types::{
+    _assoc, _dissoc, apply_comparator, atom, compare, error, hash_map, lazy_cons, lazy_seq,
+    merge_sort, num, regex, sorted_cmp, sorted_find, sorted_get, sorted_search, truthy, LineReader,
+    MalArgs, MalErr, MalList, MalMap, MalRet, MalSet, MalVal, MalVec, Sorted,
+};
 
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+// Arithmetic on two numbers: checked on ints, and on floats when either
+// argument is a float, the int being promoted.
+macro_rules! fn_arith {
+    ($name:expr, $int:ident, $float:expr) => {{
+        |a: MalArgs| match (&a[0], &a[1]) {
+            (Int(i), Int(j)) => i
+                .$int(*j)
+                .map(Int)
+                .ok_or_else(|| ErrString(format!("{}: integer overflow", $name))),
+            (x, y) => Ok(Float($float(num(x), num(y)))),
+        }
+    }};
+}
+
+macro_rules! fn_compare {
+    ($op:tt) => {{
+        |a: MalArgs| match (&a[0], &a[1]) {
+            (Int(i), Int(j)) => Ok(Bool(i $op j)),
+            (x, y) => Ok(Bool(num(x) $op num(y))),
         }
     }};
 }
@@ -44,6 +78,14 @@ macro_rules! fn_str {
     }};
 }
 
+// Printing can't fail, so lazy seqs are realized before being printed.
+fn realize_all(a: &MalArgs) -> Result<(), MalErr> {
+    for mv in a.iter() {
+        mv.realize()?;
+    }
+    Ok(())
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
         Str(ref s) => Ok(Sym(s.to_string())),
@@ -79,14 +121,240 @@ fn readline(a: MalArgs) -> MalRet {
     }
 }
 
+// The value of the optional `:name value` flag following the required
+// arguments of builtin fname.
+fn flag(fname: &str, name: &str, opts: &[MalVal]) -> Result<bool, MalErr> {
+    match opts {
+        [] => Ok(false),
+        [k, v] if k.keyword_q() && &text(k)[2..] == name => Ok(truthy(v)),
+        [k] if k.keyword_q() => Err(ErrString(format!(
+            "{}: missing value for option {}",
+            fname,
+            k.pr_str(true)
+        ))),
+        [k, ..] => Err(ErrString(format!(
+            "{}: unknown option {}",
+            fname,
+            k.pr_str(true)
+        ))),
+    }
+}
+
+// File system. Failures are catchable errors naming the function, the
+// path and the OS error, e.g. "slurp: nope.txt: No such file or
+// directory (os error 2)".
+
+fn io_error(name: &str, path: &str, e: io::Error) -> MalErr {
+    ErrString(format!("{}: {}: {}", name, path, e))
+}
+
 fn slurp(f: String) -> MalRet {
     let mut s = String::new();
-    match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
+    match File::open(&f).and_then(|mut f| f.read_to_string(&mut s)) {
         Ok(_) => Ok(Str(s)),
-        Err(e) => error(&e.to_string()),
+        Err(e) => Err(io_error("slurp", &f, e)),
+    }
+}
+
+// (spit path content) replaces the file, (spit path content :append true)
+// adds to its end. Content is converted as by str.
+fn spit(a: MalArgs) -> MalRet {
+    let path = text(&a[0]);
+    let append = flag("spit", "append", &a[2..])?;
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+    {
+        Ok(f) => f,
+        Err(e) => return Err(io_error("spit", path, e)),
+    };
+    a[1].realize()?;
+    match file.write_all(a[1].pr_str(false).as_bytes()) {
+        Ok(_) => Ok(Nil),
+        Err(e) => Err(io_error("spit", path, e)),
+    }
+}
+
+fn metadata(name: &str, path: &str) -> Result<fs::Metadata, MalErr> {
+    fs::metadata(path).map_err(|e| io_error(name, path, e))
+}
+
+// Entry names, sorted so listings are deterministic.
+fn list_dir(a: MalArgs) -> MalRet {
+    let path = text(&a[0]);
+    let entries = fs::read_dir(path)
+        .and_then(|rd| rd.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io_error("list-dir", path, e))?;
+    let mut names: Vec<String> = entries
+        .iter()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    Ok(list!(names.into_iter().map(Str).collect::<MalArgs>()))
+}
+
+// Removes a file or an empty directory.
+fn delete_file(a: MalArgs) -> MalRet {
+    let path = text(&a[0]);
+    let res = match fs::symlink_metadata(path) {
+        Ok(ref m) if m.is_dir() => fs::remove_dir(path),
+        _ => fs::remove_file(path),
+    };
+    res.map(|_| Nil)
+        .map_err(|e| io_error("delete-file", path, e))
+}
+
+fn file_mtime(a: MalArgs) -> MalRet {
+    let path = text(&a[0]);
+    let mtime = metadata("file-mtime", path)?
+        .modified()
+        .map_err(|e| io_error("file-mtime", path, e))?;
+    match mtime.duration_since(UNIX_EPOCH) {
+        Ok(d) => Ok(Int(
+            d.as_secs() as i64 * 1000 + d.subsec_nanos() as i64 / 1_000_000
+        )),
+        Err(e) => error(&format!("file-mtime: {}: {}", path, e)),
+    }
+}
+
+fn open_reader(name: &str, path: &str) -> Result<Rc<RefCell<LineReader>>, MalErr> {
+    match File::open(path) {
+        Ok(f) => Ok(Rc::new(RefCell::new(LineReader {
+            path: path.to_string(),
+            lines: Some(BufReader::new(f).lines()),
+        }))),
+        Err(e) => Err(io_error(name, path, e)),
     }
 }
 
+// The next line without its line ending, or None at end of file.
+fn next_line(name: &str, r: &Rc<RefCell<LineReader>>) -> Result<Option<String>, MalErr> {
+    let mut r = r.borrow_mut();
+    let path = r.path.clone();
+    match r.lines.as_mut().map(|lines| lines.next()) {
+        None => Err(ErrString(format!("{}: {}: reader is closed", name, path))),
+        Some(None) => Ok(None),
+        Some(Some(Ok(line))) => Ok(Some(line)),
+        Some(Some(Err(e))) => Err(io_error(name, &path, e)),
+    }
+}
+
+fn read_line(a: MalArgs) -> MalRet {
+    match a[0] {
+        Reader(ref r) => Ok(next_line("read-line", r)?.map_or(Nil, Str)),
+        _ => error("read-line: expected a reader"),
+    }
+}
+
+fn lazy_lines(r: Rc<RefCell<LineReader>>) -> MalVal {
+    lazy_seq(move || match next_line("read-lines", &r)? {
+        Some(line) => Ok(lazy_cons(Str(line), lazy_lines(r.clone()))),
+        None => Ok(Nil),
+    })
+}
+
+// A lazy seq of the lines of a file path or of an open reader. Lines are
+// read as the seq is realized, so a large file is never held in memory.
+fn read_lines(a: MalArgs) -> MalRet {
+    match a[0] {
+        Reader(ref r) => Ok(lazy_lines(r.clone())),
+        _ => Ok(lazy_lines(open_reader("read-lines", text(&a[0]))?)),
+    }
+}
+
+// Process and environment.
+
+fn exit(a: MalArgs) -> MalRet {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    process::exit(a.first().map_or(0, int) as i32)
+}
+
+fn chdir(a: MalArgs) -> MalRet {
+    let path = text(&a[0]);
+    env::set_current_dir(path)
+        .map(|_| Nil)
+        .map_err(|e| io_error("chdir", path, e))
+}
+
+// (sh cmd arg... :in "stdin text" :env {"NAME" "value"}) runs cmd
+// directly, without a shell, and waits for it. :env adds to the inherited
+// environment. Returns {:exit code :out "stdout" :err "stderr"}, with
+// :exit -1 if the command was killed by a signal.
+fn sh(a: MalArgs) -> MalRet {
+    let cmd = text(&a[0]);
+    let n = a.iter().position(|mv| mv.keyword_q()).unwrap_or(a.len());
+    let mut command = Command::new(cmd);
+    for arg in &a[1..n] {
+        match arg {
+            Str(s) => command.arg(s),
+            _ => {
+                return error(&format!(
+                    "sh: argument must be a string, got {}",
+                    arg.pr_str(true)
+                ))
+            }
+        };
+    }
+    let mut input = None;
+    for opt in a[n..].chunks(2) {
+        match (text(&opt[0]), opt.get(1)) {
+            ("\u{29e}in", Some(Str(s))) => input = Some(s.clone()),
+            ("\u{29e}env", Some(Hash(hm, _))) => {
+                for (k, v) in hm.iter() {
+                    let name = match k {
+                        Str(s) if k.keyword_q() => s[2..].to_string(),
+                        _ => k.pr_str(false),
+                    };
+                    command.env(name, v.pr_str(false));
+                }
+            }
+            ("\u{29e}in", _) => return error("sh: :in must be a string"),
+            ("\u{29e}env", _) => return error("sh: :env must be a map"),
+            _ => return error(&format!("sh: unknown option {}", opt[0].pr_str(true))),
+        }
+    }
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io_error("sh", cmd, e))?;
+    // write from another thread so a child that fills its output pipe
+    // before reading all of its input can't deadlock us
+    let writer = match (input, child.stdin.take()) {
+        (Some(s), Some(mut stdin)) => Some(thread::spawn(move || stdin.write_all(s.as_bytes()))),
+        _ => None,
+    };
+    let out = child
+        .wait_with_output()
+        .map_err(|e| io_error("sh", cmd, e))?;
+    if let Some(w) = writer {
+        // a child that exits without reading stdin closes the pipe early
+        match w.join() {
+            Ok(Err(ref e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return error(&format!("sh: {}: {}", cmd, e))
+            }
+            _ => (),
+        }
+    }
+    hash_map(vec![
+        Str("\u{29e}exit".to_string()),
+        Int(out.status.code().unwrap_or(-1) as i64),
+        Str("\u{29e}out".to_string()),
+        Str(String::from_utf8_lossy(&out.stdout).into_owned()),
+        Str("\u{29e}err".to_string()),
+        Str(String::from_utf8_lossy(&out.stderr).into_owned()),
+    ])
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -100,85 +368,278 @@ fn time_ms(_a: MalArgs) -> MalRet {
 fn get(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+        (Hash(ref hm, _), ref k) => match hm.get(k) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
+        (Set(ref hs, _), ref k) if hs.contains(k) => Ok(k.clone()),
+        (Set(..), _) => Ok(Nil),
+        (SortedMap(ref s, _), ref k) => {
+            Ok(sorted_get(s, k)?.map_or(Nil, |i| s.entries[i].1.clone()))
+        }
+        (SortedSet(ref s, _), ref k) => {
+            Ok(sorted_get(s, k)?.map_or(Nil, |i| s.entries[i].0.clone()))
+        }
         _ => error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
+        Hash(ref hm, _) => _assoc(hm.clone(), a[1..].to_vec()),
+        SortedMap(ref s, _) => Ok(SortedMap(sorted_assoc(s.clone(), &a[1..])?, Rc::new(Nil))),
         _ => error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
+        Hash(ref hm, _) => _dissoc(hm.clone(), a[1..].to_vec()),
+        SortedMap(ref s, _) => Ok(SortedMap(sorted_dissoc(s.clone(), &a[1..])?, Rc::new(Nil))),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
+        (Hash(ref hm, _), ref k) => Ok(Bool(hm.contains_key(k))),
+        (Set(ref hs, _), ref k) => Ok(Bool(hs.contains(k))),
+        (SortedMap(ref s, _), ref k) | (SortedSet(ref s, _), ref k) => {
+            Ok(Bool(sorted_get(s, k)?.is_some()))
+        }
         _ => error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect::<MalArgs>())),
+        SortedMap(ref s, _) => Ok(list!(s
+            .entries
+            .iter()
+            .map(|e| e.0.clone())
+            .collect::<MalArgs>())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect::<MalArgs>())),
+        SortedMap(ref s, _) => Ok(list!(s
+            .entries
+            .iter()
+            .map(|e| e.1.clone())
+            .collect::<MalArgs>())),
         _ => error("keys requires Hash Map"),
     }
 }
 
+// Sets. The set algebra functions also take nil as the empty set.
+
+fn elems(mv: &MalVal) -> MalSet {
+    mv.set_elems().unwrap_or_default()
+}
+
+fn set(a: MalArgs) -> MalRet {
+    Ok(Set(
+        as_seq(&a[0]).seq_vec()?.into_iter().collect(),
+        Rc::new(Nil),
+    ))
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Nil => Ok(Nil),
+        SortedSet(ref s, _) => Ok(SortedSet(sorted_dissoc(s.clone(), &a[1..])?, Rc::new(Nil))),
+        _ => {
+            let mut hs = elems(&a[0]);
+            for mv in a[1..].iter() {
+                hs.remove(mv);
+            }
+            Ok(Set(hs, Rc::new(Nil)))
+        }
+    }
+}
+
+fn union(a: MalArgs) -> MalRet {
+    let mut hs = MalSet::default();
+    for s in a.iter() {
+        hs.extend(elems(s));
+    }
+    Ok(Set(hs, Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let sets: Vec<MalSet> = a.iter().map(elems).collect();
+    let hs = sets[0]
+        .iter()
+        .filter(|mv| sets[1..].iter().all(|s| s.contains(mv)))
+        .cloned()
+        .collect();
+    Ok(Set(hs, Rc::new(Nil)))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let sets: Vec<MalSet> = a.iter().map(elems).collect();
+    let hs = sets[0]
+        .iter()
+        .filter(|mv| !sets[1..].iter().any(|s| s.contains(mv)))
+        .cloned()
+        .collect();
+    Ok(Set(hs, Rc::new(Nil)))
+}
+
+// Sorted maps and sets. Keys that cmp orders as equal are the same key.
+
+fn sorted_assoc(mut s: Sorted, kvs: &[MalVal]) -> Result<Sorted, MalErr> {
+    if !kvs.len().is_multiple_of(2) {
+        return Err(ErrString("odd number of elements".to_string()));
+    }
+    for (k, v) in kvs.iter().tuples() {
+        match sorted_find(&s, k)? {
+            Ok(i) => s.entries[i].1 = v.clone(),
+            Err(i) => s.entries.insert(i, (k.clone(), v.clone())),
+        }
+    }
+    Ok(s)
+}
+
+fn sorted_conj(mut s: Sorted, ks: &[MalVal]) -> Result<Sorted, MalErr> {
+    for k in ks.iter() {
+        if let Err(i) = sorted_find(&s, k)? {
+            s.entries.insert(i, (k.clone(), Nil));
+        }
+    }
+    Ok(s)
+}
+
+fn sorted_dissoc(mut s: Sorted, ks: &[MalVal]) -> Result<Sorted, MalErr> {
+    for k in ks.iter() {
+        if let Ok(i) = sorted_find(&s, k)? {
+            s.entries.remove(i);
+        }
+    }
+    Ok(s)
+}
+
+fn sorted(cmp: &MalVal) -> Sorted {
+    Sorted {
+        cmp: Rc::new(cmp.clone()),
+        entries: ImVector::new(),
+    }
+}
+
+// (subseq sc test key) and (subseq sc start-test start-key end-test
+// end-key) give the entries of sorted collection sc, in order, for which
+// (test (cmp entry-key key) 0) holds, where test is one of < <= > >= and
+// cmp is the collection's comparator. rsubseq gives them in reverse.
+fn subseq(name: &str, a: &MalArgs) -> Result<MalArgs, MalErr> {
+    if a.len() != 3 && a.len() != 5 {
+        return Err(ErrString(format!(
+            "{}: wrong number of arguments, expected 3 or 5, got {}",
+            name,
+            a.len()
+        )));
+    }
+    let s = match a[0] {
+        SortedMap(ref s, _) | SortedSet(ref s, _) => s,
+        _ => {
+            return Err(ErrString(format!(
+                "{}: expected a sorted map or set, got {}",
+                name,
+                a[0].pr_str(true)
+            )))
+        }
+    };
+    let (mut lo, mut hi) = (0, s.entries.len());
+    for (test, key) in a[1..].iter().tuples() {
+        let passes = |e: &MalVal| -> Result<bool, MalErr> {
+            let ord = sorted_cmp(s, e, key)? as i64;
+            Ok(truthy(&test.apply(vec![Int(ord), Int(0)])?))
+        };
+        // > and >= hold from some key onwards, < and <= up to some key
+        if truthy(&test.apply(vec![Int(1), Int(0)])?) {
+            lo = lo.max(sorted_search(s, |e| passes(e))?);
+        } else {
+            hi = hi.min(sorted_search(s, |e| Ok(!passes(e)?))?);
+        }
+    }
+    let is_map = matches!(a[0], SortedMap(..));
+    Ok(s.entries
+        .iter()
+        .skip(lo)
+        .take(hi.saturating_sub(lo))
+        .map(|(k, v)| {
+            if is_map {
+                vector![vec![k.clone(), v.clone()]]
+            } else {
+                k.clone()
+            }
+        })
+        .collect())
+}
+
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
+        List(ref l, _) => Ok(vector!(l.iter().cloned().collect::<MalVec>())),
+        Vector(ref v, _) => Ok(vector!(v.clone())),
+        LazySeq(..) | Set(..) | SortedSet(..) => Ok(vector!(a[0].seq_vec()?)),
         _ => error("non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
     match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
-        }
+        List(l, _) => Ok(list!(l.cons(a[0].clone()))),
+        Vector(v, _) => Ok(list!(MalList::from(v).cons(a[0].clone()))),
+        LazySeq(..) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
         _ => error("cons expects seq as second arg"),
     }
 }
 
+// The last seq is shared as the tail of the result, so it stays lazy if
+// it was; only the ones before it are copied.
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
-    for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
-        }
+    let mut items = vec![];
+    for seq in a.iter().take(a.len().saturating_sub(1)) {
+        items.extend(seq.seq_vec()?);
+    }
+    let items = items.into_iter().rev();
+    match a.last() {
+        None => Ok(list![]),
+        Some(List(l, _)) => Ok(list!(items.fold(l.clone(), |l, mv| l.cons(mv)))),
+        Some(Vector(v, _)) => Ok(list!(
+            items.fold(MalList::from(v.clone()), |l, mv| l.cons(mv))
+        )),
+        Some(tail @ LazySeq(..)) => Ok(items.fold(tail.clone(), |s, mv| lazy_cons(mv, s))),
+        _ => error("non-seq passed to concat"),
     }
-    Ok(list!(new_v.to_vec()))
 }
 
 fn nth(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
-            if seq.len() <= idx as usize {
-                return error("nth: index out of range");
+        (_, Int(idx)) if idx < 0 => error("nth: index out of range"),
+        (List(l, _), Int(idx)) => match l.get(idx as usize) {
+            Some(mv) => Ok(mv.clone()),
+            None => error("nth: index out of range"),
+        },
+        (Vector(v, _), Int(idx)) => match v.get(idx as usize) {
+            Some(mv) => Ok(mv.clone()),
+            None => error("nth: index out of range"),
+        },
+        (seq @ LazySeq(..), Int(idx)) => {
+            let mut seq = seq;
+            for _ in 0..idx {
+                match seq.uncons()? {
+                    Some((_, rest)) => seq = rest,
+                    None => break,
+                }
+            }
+            match seq.uncons()? {
+                Some((mv, _)) => Ok(mv),
+                None => error("nth: index out of range"),
             }
-            Ok(seq[idx as usize].clone())
         }
         _ => error("invalid args to nth"),
     }
@@ -186,8 +647,11 @@ fn nth(a: MalArgs) -> MalRet {
 
 fn first(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
+        List(ref l, _) => Ok(l.first().unwrap_or(&Nil).clone()),
+        Vector(ref v, _) => Ok(v.front().unwrap_or(&Nil).clone()),
+        LazySeq(..) | Hash(..) | Set(..) | SortedMap(..) | SortedSet(..) => {
+            Ok(a[0].uncons()?.map_or(Nil, |(mv, _)| mv))
+        }
         Nil => Ok(Nil),
         _ => error("invalid args to first"),
     }
@@ -195,12 +659,10 @@ fn first(a: MalArgs) -> MalRet {
 
 fn rest(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
+        List(ref l, _) => Ok(list!(l.rest())),
+        Vector(ref v, _) => Ok(list!(MalList::from(v.clone()).rest())),
+        LazySeq(..) | Hash(..) | Set(..) | SortedMap(..) | SortedSet(..) => {
+            Ok(a[0].uncons()?.map_or(list![], |(_, rest)| rest))
         }
         Nil => Ok(list![]),
         _ => error("invalid args to first"),
@@ -209,143 +671,1795 @@ fn rest(a: MalArgs) -> MalRet {
 
 fn apply(a: MalArgs) -> MalRet {
     match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
+        List(..) | Vector(..) | LazySeq(..) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(a[a.len() - 1].seq_vec()?);
             f.apply(fargs)
         }
         _ => error("apply called with non-seq"),
     }
 }
 
-fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
-            }
-            Ok(list!(res))
-        }
-        _ => error("map called with non-seq"),
-    }
-}
-
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
-        }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
+        List(ref l, _) => Ok(list!(a[1..]
+            .iter()
+            .fold(l.clone(), |l, mv| l.cons(mv.clone())))),
+        Vector(ref v, _) => {
+            let mut new_v = v.clone();
+            for mv in a[1..].iter() {
+                new_v.push_back(mv.clone());
+            }
+            Ok(vector!(new_v))
+        }
+        LazySeq(..) => Ok(a[1..]
+            .iter()
+            .fold(a[0].clone(), |s, mv| lazy_cons(mv.clone(), s))),
+        Set(ref hs, _) => {
+            let mut new_hs = hs.clone();
+            for mv in a[1..].iter() {
+                new_hs.insert(mv.clone());
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
+        }
+        SortedSet(ref s, _) => Ok(SortedSet(sorted_conj(s.clone(), &a[1..])?, Rc::new(Nil))),
         _ => error("conj: called with non-seq"),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
+        List(ref l, _) if l.is_empty() => Ok(Nil),
+        List(ref l, _) => Ok(list!(l.clone())),
+        Vector(ref v, _) if v.is_empty() => Ok(Nil),
+        Vector(ref v, _) => Ok(list!(MalList::from(v.clone()))),
+        LazySeq(..) => Ok(a[0].uncons()?.map_or(Nil, |_| a[0].clone())),
+        Set(ref hs, _) if hs.is_empty() => Ok(Nil),
+        Set(ref hs, _) => Ok(list!(hs.iter().cloned().collect::<MalArgs>())),
+        Hash(ref hm, _) if hm.is_empty() => Ok(Nil),
+        SortedMap(ref s, _) | SortedSet(ref s, _) if s.entries.is_empty() => Ok(Nil),
+        Hash(..) | SortedMap(..) | SortedSet(..) => Ok(list!(a[0].seq_vec()?)),
         Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
-        }
+        Str(ref s) if !a[0].keyword_q() => Ok(list!(s
+            .chars()
+            .map(|c| { Str(c.to_string()) })
+            .collect::<MalArgs>())),
         Nil => Ok(Nil),
         _ => error("seq: called with non-seq"),
     }
 }
 
+// Lazy sequence constructors. Each returns an unrealized seq whose thunk
+// computes one element and the (again lazy) rest.
+
+fn lazy_range(start: i64, end: Option<i64>, step: i64) -> MalVal {
+    lazy_seq(move || {
+        let done = match end {
+            Some(end) if step < 0 => start <= end,
+            Some(end) => start >= end,
+            None => false,
+        };
+        if done {
+            return Ok(Nil);
+        }
+        // the seq ends at the last value that fits in an int
+        let rest = match start.checked_add(step) {
+            Some(next) => lazy_range(next, end, step),
+            None => Nil,
+        };
+        Ok(lazy_cons(Int(start), rest))
+    })
+}
+
+fn range(a: MalArgs) -> MalRet {
+    let ints: Vec<i64> = a
+        .iter()
+        .map(|mv| match mv {
+            Int(i) => *i,
+            _ => 0,
+        })
+        .collect();
+    Ok(match ints.len() {
+        0 => lazy_range(0, None, 1),
+        1 => lazy_range(0, Some(ints[0]), 1),
+        2 => lazy_range(ints[0], Some(ints[1]), 1),
+        _ => lazy_range(ints[0], Some(ints[1]), ints[2]),
+    })
+}
+
+fn lazy_iterate(f: MalVal, x: MalVal) -> MalVal {
+    let rest = {
+        let x = x.clone();
+        lazy_seq(move || Ok(lazy_iterate(f.clone(), f.apply(vec![x.clone()])?)))
+    };
+    lazy_cons(x, rest)
+}
+
+fn iterate(a: MalArgs) -> MalRet {
+    Ok(lazy_iterate(a[0].clone(), a[1].clone()))
+}
+
+fn lazy_repeat(x: MalVal) -> MalVal {
+    lazy_seq(move || Ok(lazy_cons(x.clone(), lazy_repeat(x.clone()))))
+}
+
+fn repeat(a: MalArgs) -> MalRet {
+    match a[0] {
+        _ if a.len() == 1 => Ok(lazy_repeat(a[0].clone())),
+        Int(_) => Ok(lazy_take(a[0].clone(), lazy_repeat(a[1].clone()))),
+        _ => error("repeat: count must be an int"),
+    }
+}
+
+fn lazy_cycle(items: Rc<MalArgs>, i: usize) -> MalVal {
+    let first = items[i].clone();
+    let rest = lazy_seq(move || Ok(lazy_cycle(items.clone(), (i + 1) % items.len())));
+    lazy_cons(first, rest)
+}
+
+fn cycle(a: MalArgs) -> MalRet {
+    let seq = a[0].clone();
+    Ok(lazy_seq(move || {
+        let items = seq.seq_vec()?;
+        if items.is_empty() {
+            Ok(Nil)
+        } else {
+            Ok(lazy_cycle(Rc::new(items), 0))
+        }
+    }))
+}
+
+fn lazy_take(n: MalVal, seq: MalVal) -> MalVal {
+    lazy_seq(move || match n {
+        Int(n) if n <= 0 => Ok(Nil),
+        Int(n) => match seq.uncons()? {
+            Some((mv, rest)) => Ok(lazy_cons(mv, lazy_take(Int(n - 1), rest))),
+            None => Ok(Nil),
+        },
+        _ => error("take: count must be an int"),
+    })
+}
+
+fn lazy_drop(n: MalVal, seq: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut seq = seq.clone();
+        if let Int(n) = n {
+            for _ in 0..n {
+                match seq.uncons()? {
+                    Some((_, rest)) => seq = rest,
+                    None => return Ok(Nil),
+                }
+            }
+        }
+        Ok(seq)
+    })
+}
+
+fn lazy_take_while(pred: MalVal, seq: MalVal) -> MalVal {
+    lazy_seq(move || match seq.uncons()? {
+        Some((mv, rest)) if truthy(&pred.apply(vec![mv.clone()])?) => {
+            Ok(lazy_cons(mv, lazy_take_while(pred.clone(), rest)))
+        }
+        _ => Ok(Nil),
+    })
+}
+
+// Sequence library. Every function takes any collection: list, vector,
+// lazy seq, nil or string. Seqs returned are lazy when an input is lazy,
+// so they work on infinite seqs; otherwise they are realized up front.
+
+// A string is a seq of its characters.
+fn as_seq(mv: &MalVal) -> MalVal {
+    match mv {
+        Str(s) if !mv.keyword_q() => {
+            list!(s.chars().map(|c| Str(c.to_string())).collect::<MalArgs>())
+        }
+        _ => mv.clone(),
+    }
+}
+
+fn finish(colls: &[MalVal], res: MalVal) -> MalRet {
+    let lazy = colls.iter().any(|c| matches!(c, LazySeq(..)));
+    if lazy {
+        Ok(res)
+    } else {
+        Ok(list!(res.seq_vec()?))
+    }
+}
+
+fn lazy_map(f: MalVal, seqs: MalArgs) -> MalVal {
+    lazy_seq(move || {
+        let mut args = vec![];
+        let mut rests = vec![];
+        for seq in seqs.iter() {
+            match seq.uncons()? {
+                Some((mv, rest)) => {
+                    args.push(mv);
+                    rests.push(rest);
+                }
+                None => return Ok(Nil),
+            }
+        }
+        Ok(lazy_cons(f.apply(args)?, lazy_map(f.clone(), rests)))
+    })
+}
+
+fn map(a: MalArgs) -> MalRet {
+    let seqs = a[1..].iter().map(as_seq).collect();
+    finish(&a[1..], lazy_map(a[0].clone(), seqs))
+}
+
+fn lazy_filter(pred: MalVal, keep: bool, seq: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut seq = seq.clone();
+        while let Some((mv, rest)) = seq.uncons()? {
+            if truthy(&pred.apply(vec![mv.clone()])?) == keep {
+                return Ok(lazy_cons(mv, lazy_filter(pred.clone(), keep, rest)));
+            }
+            seq = rest;
+        }
+        Ok(Nil)
+    })
+}
+
+fn filter(a: MalArgs) -> MalRet {
+    finish(&a[1..], lazy_filter(a[0].clone(), true, as_seq(&a[1])))
+}
+
+fn remove(a: MalArgs) -> MalRet {
+    finish(&a[1..], lazy_filter(a[0].clone(), false, as_seq(&a[1])))
+}
+
+// The elements of each seq in seqs, one after the other.
+fn lazy_cat(seq: MalVal, seqs: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let (mut seq, mut seqs) = (seq.clone(), seqs.clone());
+        loop {
+            if let Some((mv, rest)) = seq.uncons()? {
+                return Ok(lazy_cons(mv, lazy_cat(rest, seqs)));
+            }
+            match seqs.uncons()? {
+                Some((next, more)) => {
+                    seq = as_seq(&next);
+                    seqs = more;
+                }
+                None => return Ok(Nil),
+            }
+        }
+    })
+}
+
+fn mapcat(a: MalArgs) -> MalRet {
+    let seqs = a[1..].iter().map(as_seq).collect();
+    finish(&a[1..], lazy_cat(Nil, lazy_map(a[0].clone(), seqs)))
+}
+
+fn lazy_interleave(seqs: MalArgs) -> MalVal {
+    lazy_seq(move || {
+        let mut firsts = vec![];
+        let mut rests = vec![];
+        for seq in seqs.iter() {
+            match seq.uncons()? {
+                Some((mv, rest)) => {
+                    firsts.push(mv);
+                    rests.push(rest);
+                }
+                None => return Ok(Nil),
+            }
+        }
+        if firsts.is_empty() {
+            return Ok(Nil);
+        }
+        let rest = lazy_interleave(rests);
+        Ok(firsts
+            .into_iter()
+            .rev()
+            .fold(rest, |s, mv| lazy_cons(mv, s)))
+    })
+}
+
+fn interleave(a: MalArgs) -> MalRet {
+    finish(&a, lazy_interleave(a.iter().map(as_seq).collect()))
+}
+
+fn lazy_distinct(seen: MalMap, seq: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut seq = seq.clone();
+        while let Some((mv, rest)) = seq.uncons()? {
+            if !seen.contains_key(&mv) {
+                let seen = seen.update(mv.clone(), Nil);
+                return Ok(lazy_cons(mv, lazy_distinct(seen, rest)));
+            }
+            seq = rest;
+        }
+        Ok(Nil)
+    })
+}
+
+fn distinct(a: MalArgs) -> MalRet {
+    finish(&a, lazy_distinct(MalMap::default(), as_seq(&a[0])))
+}
+
+// Partitions of n elements starting every step elements. A short last
+// partition is dropped, unless pad is given to fill it from.
+fn lazy_partition(n: i64, step: i64, pad: Option<MalVal>, seq: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut part = vec![];
+        let mut s = seq.clone();
+        while (part.len() as i64) < n {
+            match s.uncons()? {
+                Some((mv, rest)) => {
+                    part.push(mv);
+                    s = rest;
+                }
+                None => break,
+            }
+        }
+        if part.is_empty() {
+            return Ok(Nil);
+        }
+        if (part.len() as i64) < n {
+            return match pad {
+                Some(ref pad) => {
+                    let fill = n as usize - part.len();
+                    part.extend(pad.seq_vec()?.into_iter().take(fill));
+                    Ok(lazy_cons(list!(part), Nil))
+                }
+                None => Ok(Nil),
+            };
+        }
+        let mut next = seq.clone();
+        for _ in 0..step {
+            match next.uncons()? {
+                Some((_, rest)) => next = rest,
+                None => break,
+            }
+        }
+        Ok(lazy_cons(
+            list!(part),
+            lazy_partition(n, step, pad.clone(), next),
+        ))
+    })
+}
+
+fn partition(a: MalArgs) -> MalRet {
+    let (n, step, pad) = match a.len() {
+        2 => {
+            check_args("partition", 2, 2, &[INT, COLL], &a)?;
+            (a[0].clone(), a[0].clone(), None)
+        }
+        3 => {
+            check_args("partition", 3, 3, &[INT, INT, COLL], &a)?;
+            (a[0].clone(), a[1].clone(), None)
+        }
+        _ => {
+            check_args("partition", 4, 4, &[INT, INT, COLL], &a)?;
+            (a[0].clone(), a[1].clone(), Some(as_seq(&a[2])))
+        }
+    };
+    let coll = &a[a.len() - 1];
+    match (n, step) {
+        (Int(n), Int(step)) if n > 0 && step > 0 => finish(
+            ::std::slice::from_ref(coll),
+            lazy_partition(n, step, pad, as_seq(coll)),
+        ),
+        _ => error("partition: size and step must be positive"),
+    }
+}
+
+fn reduce(a: MalArgs) -> MalRet {
+    if a.len() == 2 {
+        check_args("reduce", 2, 2, &[FN, COLL], &a)?;
+    }
+    let f = &a[0];
+    let coll = as_seq(&a[a.len() - 1]);
+    let (mut acc, mut seq) = match coll.uncons()? {
+        _ if a.len() == 3 => (a[1].clone(), coll),
+        Some((mv, rest)) => (mv, rest),
+        None => return f.apply(vec![]),
+    };
+    while let Some((mv, rest)) = seq.uncons()? {
+        acc = f.apply(vec![acc, mv])?;
+        seq = rest;
+    }
+    Ok(acc)
+}
+
+fn some(a: MalArgs) -> MalRet {
+    let mut seq = as_seq(&a[1]);
+    while let Some((mv, rest)) = seq.uncons()? {
+        let res = a[0].apply(vec![mv])?;
+        if truthy(&res) {
+            return Ok(res);
+        }
+        seq = rest;
+    }
+    Ok(Nil)
+}
+
+fn every_q(a: MalArgs) -> MalRet {
+    let mut seq = as_seq(&a[1]);
+    while let Some((mv, rest)) = seq.uncons()? {
+        if !truthy(&a[0].apply(vec![mv])?) {
+            return Ok(Bool(false));
+        }
+        seq = rest;
+    }
+    Ok(Bool(true))
+}
+
+// A hash of a value consistent with =: equal values hash alike.
+fn hash(a: MalArgs) -> MalRet {
+    a[0].realize()?;
+    let mut h = FnvHasher::default();
+    a[0].hash(&mut h);
+    Ok(Int(h.finish() as i64))
+}
+
+// (max-key k x & xs) is the x for which (k x) is greatest by compare,
+// the last of them on a tie; min-key likewise picks the least.
+fn extreme_key(a: &MalArgs, keep: Ordering) -> MalRet {
+    let mut best = a[1].clone();
+    let mut best_key = a[0].apply(vec![best.clone()])?;
+    for mv in a[2..].iter() {
+        let key = a[0].apply(vec![mv.clone()])?;
+        if compare(&key, &best_key)? != keep.reverse() {
+            best = mv.clone();
+            best_key = key;
+        }
+    }
+    Ok(best)
+}
+
+fn sort(a: MalArgs) -> MalRet {
+    let items = match a.len() {
+        1 => {
+            check_args("sort", 1, 1, &[COLL], &a)?;
+            merge_sort(as_seq(&a[0]).seq_vec()?, &compare)?
+        }
+        _ => {
+            check_args("sort", 2, 2, &[FN, COLL], &a)?;
+            let cmp = |x: &MalVal, y: &MalVal| apply_comparator(&a[0], x, y);
+            merge_sort(as_seq(&a[1]).seq_vec()?, &cmp)?
+        }
+    };
+    Ok(list!(items))
+}
+
+fn sort_by(a: MalArgs) -> MalRet {
+    if a.len() == 3 {
+        check_args("sort-by", 3, 3, &[FN, FN, COLL], &a)?;
+    }
+    let keyed = as_seq(&a[a.len() - 1])
+        .seq_vec()?
+        .into_iter()
+        .map(|mv| Ok((a[0].apply(vec![mv.clone()])?, mv)))
+        .collect::<Result<Vec<(MalVal, MalVal)>, MalErr>>()?;
+    let items = match a.len() {
+        2 => merge_sort(keyed, &|x: &(MalVal, MalVal), y: &(MalVal, MalVal)| {
+            compare(&x.0, &y.0)
+        })?,
+        _ => merge_sort(keyed, &|x: &(MalVal, MalVal), y: &(MalVal, MalVal)| {
+            apply_comparator(&a[1], &x.0, &y.0)
+        })?,
+    };
+    Ok(list!(items
+        .into_iter()
+        .map(|(_, mv)| mv)
+        .collect::<MalArgs>()))
+}
+
+fn group_by(a: MalArgs) -> MalRet {
+    let mut groups = MalMap::default();
+    for mv in as_seq(&a[1]).seq_vec()? {
+        let k = a[0].apply(vec![mv.clone()])?;
+        let mut group = match groups.get(&k) {
+            Some(Vector(v, _)) => v.clone(),
+            _ => MalVec::new(),
+        };
+        group.push_back(mv);
+        groups.insert(k, vector!(group));
+    }
+    Ok(Hash(groups, Rc::new(Nil)))
+}
+
+fn frequencies(a: MalArgs) -> MalRet {
+    let mut counts = MalMap::default();
+    for mv in as_seq(&a[0]).seq_vec()? {
+        let n = match counts.get(&mv) {
+            Some(Int(n)) => *n,
+            _ => 0,
+        };
+        counts.insert(mv, Int(n + 1));
+    }
+    Ok(Hash(counts, Rc::new(Nil)))
+}
+
+fn reverse(a: MalArgs) -> MalRet {
+    let items = as_seq(&a[0]).seq_vec()?;
+    Ok(list!(items
+        .into_iter()
+        .fold(MalList::new(), |l, mv| l.cons(mv))))
+}
+
+fn last(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref l, _) => Ok(l.last().unwrap_or(&Nil).clone()),
+        Vector(ref v, _) => Ok(v.back().unwrap_or(&Nil).clone()),
+        _ => Ok(as_seq(&a[0]).seq_vec()?.pop().unwrap_or(Nil)),
+    }
+}
+
+fn butlast(a: MalArgs) -> MalRet {
+    let mut items = as_seq(&a[0]).seq_vec()?;
+    if items.len() <= 1 {
+        return Ok(Nil);
+    }
+    items.pop();
+    Ok(list!(items))
+}
+
+// String functions. Positions are counted in characters, not bytes, and
+// any position not inside the string is an error.
+
+fn text(mv: &MalVal) -> &str {
+    match mv {
+        Str(s) => s,
+        _ => "",
+    }
+}
+
+fn int(mv: &MalVal) -> i64 {
+    match mv {
+        Int(i) => *i,
+        _ => 0,
+    }
+}
+
+// Byte offset of character i of s; i may be the length of s.
+fn byte_index(s: &str, i: i64) -> Option<usize> {
+    if i < 0 {
+        return None;
+    }
+    s.char_indices()
+        .map(|(b, _)| b)
+        .chain(iter::once(s.len()))
+        .nth(i as usize)
+}
+
+fn subs(a: MalArgs) -> MalRet {
+    let s = text(&a[0]);
+    let len = s.chars().count() as i64;
+    let (start, end) = (int(&a[1]), a.get(2).map_or(len, int));
+    match (byte_index(s, start), byte_index(s, end)) {
+        (Some(b), Some(e)) if start <= end => Ok(Str(s[b..e].to_string())),
+        _ => error(&format!(
+            "subs: range {} to {} out of bounds for string of length {}",
+            start, end, len
+        )),
+    }
+}
+
+fn split(a: MalArgs) -> MalRet {
+    let s = text(&a[0]);
+    let parts: MalArgs = match a[1] {
+        Regex(ref re) => re.split(s).map(|p| Str(p.to_string())).collect(),
+        Str(ref sep) if sep.is_empty() => s.chars().map(|c| Str(c.to_string())).collect(),
+        _ => s.split(text(&a[1])).map(|p| Str(p.to_string())).collect(),
+    };
+    Ok(vector!(parts))
+}
+
+// The match is replaced by a string, where a regex match may refer to its
+// groups as $1 or ${name}, or by what a function returns for the match.
+fn replace(a: MalArgs) -> MalRet {
+    let s = text(&a[0]);
+    match (&a[1], &a[2]) {
+        (Regex(re), Str(r)) => Ok(Str(re.replace_all(s, r.as_str()).to_string())),
+        (Str(m), Str(r)) => Ok(Str(s.replace(m.as_str(), r))),
+        (Regex(re), f) => replace_with(re, s, f),
+        (m, f) => {
+            let re = regex(&::regex::escape(text(m)))?;
+            replace_with(&re, s, f)
+        }
+    }
+}
+
+fn join(a: MalArgs) -> MalRet {
+    if a.len() == 2 {
+        check_args("join", 2, 2, &[STRING, COLL], &a)?;
+    }
+    let sep = if a.len() == 2 { text(&a[0]) } else { "" };
+    let items = as_seq(&a[a.len() - 1]).seq_vec()?;
+    Ok(Str(pr_seq(&items, false, "", "", sep)))
+}
+
+fn index_of(a: MalArgs) -> MalRet {
+    let (s, sub) = (text(&a[0]), text(&a[1]));
+    let from = a.get(2).map_or(0, int);
+    let start = match byte_index(s, from) {
+        Some(b) => b,
+        None => return error(&format!("index-of: index {} out of bounds", from)),
+    };
+    match s[start..].find(sub) {
+        Some(b) => Ok(Int(s[..start + b].chars().count() as i64)),
+        None => Ok(Nil),
+    }
+}
+
+fn char_code(a: MalArgs) -> MalRet {
+    let mut chars = text(&a[0]).chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Int(c as i64)),
+        _ => error(&format!(
+            "char-code: expected a single character, got {}",
+            a[0].pr_str(true)
+        )),
+    }
+}
+
+fn from_char_code(a: MalArgs) -> MalRet {
+    let n = int(&a[0]);
+    match std::char::from_u32(n as u32) {
+        Some(c) if n >= 0 && n <= u32::MAX as i64 => Ok(Str(c.to_string())),
+        _ => error(&format!("from-char-code: invalid code point {}", n)),
+    }
+}
+
+// printf-style formatting: %[flags][width][.precision]conversion with
+// flags - (left-justify), 0 (zero pad), + and space (sign of numbers).
+// %s and %S print any value as str and pr-str do, %d and %x an int, and
+// %f, %e and %g a number.
+
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+fn pad(spec: &Spec, sign: &str, body: String, numeric: bool) -> String {
+    let len = sign.chars().count() + body.chars().count();
+    let fill = spec.width.saturating_sub(len);
+    if spec.left {
+        format!("{}{}{}", sign, body, " ".repeat(fill))
+    } else if spec.zero && numeric {
+        format!("{}{}{}", sign, "0".repeat(fill), body)
+    } else {
+        format!("{}{}{}", " ".repeat(fill), sign, body)
+    }
+}
+
+fn format_int(spec: &Spec, conv: char, i: i64) -> String {
+    let sign = match i {
+        _ if i < 0 => "-",
+        _ if spec.plus => "+",
+        _ if spec.space => " ",
+        _ => "",
+    };
+    let n = i.unsigned_abs();
+    let body = match conv {
+        'x' => format!("{:x}", n),
+        'f' => format!("{}.{}", n, "0".repeat(spec.precision.unwrap_or(6)))
+            .trim_end_matches('.')
+            .to_string(),
+        _ => n.to_string(),
+    };
+    pad(spec, sign, body, true)
+}
+
+fn format_float(spec: &Spec, conv: char, f: f64) -> String {
+    let sign = match f {
+        _ if f.is_sign_negative() && !f.is_nan() => "-",
+        _ if spec.plus => "+",
+        _ if spec.space => " ",
+        _ => "",
+    };
+    let x = f.abs();
+    if !x.is_finite() {
+        let body = if x.is_nan() { "nan" } else { "inf" };
+        return pad(spec, sign, body.to_string(), false);
+    }
+    let p = spec.precision.unwrap_or(6);
+    let body = match conv {
+        'e' => format_exp(x, p),
+        'g' => {
+            // %e if the exponent is below -4 or not below the precision,
+            // else %f, and either way without trailing zeros
+            let p = p.max(1);
+            let e = exp_of(x, p - 1);
+            let s = if e < -4 || e >= p as i32 {
+                format_exp(x, p - 1)
+            } else {
+                format!("{:.*}", (p as i32 - 1 - e) as usize, x)
+            };
+            match s.find('e') {
+                Some(i) if s[..i].contains('.') => format!(
+                    "{}{}",
+                    s[..i].trim_end_matches('0').trim_end_matches('.'),
+                    &s[i..]
+                ),
+                None if s.contains('.') => {
+                    s.trim_end_matches('0').trim_end_matches('.').to_string()
+                }
+                _ => s,
+            }
+        }
+        _ => format!("{:.*}", p, x),
+    };
+    pad(spec, sign, body, true)
+}
+
+// The decimal exponent of x once rounded to p digits after the point.
+fn exp_of(x: f64, p: usize) -> i32 {
+    let s = format!("{:.*e}", p, x);
+    s[s.find('e').unwrap() + 1..].parse().unwrap_or(0)
+}
+
+// x as d.ddde+XX, the exponent with a sign and at least two digits.
+fn format_exp(x: f64, p: usize) -> String {
+    let s = format!("{:.*e}", p, x);
+    let (mantissa, e) = s.split_at(s.find('e').unwrap());
+    let e: i32 = e[1..].parse().unwrap_or(0);
+    let sign = if e < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, e.abs())
+}
+
+fn format_str(fmt: &str, args: &[MalVal]) -> Result<String, MalErr> {
+    let mut res = String::new();
+    let mut next = 0;
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            res.push(c);
+            continue;
+        }
+        let mut spec = Spec {
+            left: false,
+            zero: false,
+            plus: false,
+            space: false,
+            width: 0,
+            precision: None,
+        };
+        while let Some(&f) = chars.peek() {
+            match f {
+                '-' => spec.left = true,
+                '0' => spec.zero = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+            spec.width = spec.width * 10 + d as usize;
+            chars.next();
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut p = 0;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                p = p * 10 + d as usize;
+                chars.next();
+            }
+            spec.precision = Some(p);
+        }
+        let conv = match chars.next() {
+            Some('%') => {
+                res.push('%');
+                continue;
+            }
+            Some(conv) => conv,
+            None => {
+                return Err(ErrString(
+                    "format: incomplete directive at end of format string".to_string(),
+                ))
+            }
+        };
+        if !"sSdxfeg".contains(conv) {
+            return Err(ErrString(format!("format: unknown directive %{}", conv)));
+        }
+        let arg = match args.get(next) {
+            Some(arg) => arg,
+            None => {
+                return Err(ErrString(format!(
+                    "format: not enough arguments, %{} has no argument {}",
+                    conv,
+                    next + 1
+                )))
+            }
+        };
+        next += 1;
+        arg.realize()?;
+        res.push_str(&match (conv, arg) {
+            ('s', _) | ('S', _) => {
+                let s = arg.pr_str(conv == 'S');
+                let s = match spec.precision {
+                    Some(p) => s.chars().take(p).collect(),
+                    None => s,
+                };
+                pad(&spec, "", s, false)
+            }
+            ('e', Int(_)) | ('g', Int(_)) | ('f', Float(_)) | ('e', Float(_)) | ('g', Float(_)) => {
+                format_float(&spec, conv, num(arg))
+            }
+            (_, Int(i)) => format_int(&spec, conv, *i),
+            _ => {
+                return Err(ErrString(format!(
+                    "format: %{} expects {}, got {}",
+                    conv,
+                    if "feg".contains(conv) {
+                        "a number"
+                    } else {
+                        "an int"
+                    },
+                    ty_names(ty_of(arg)).join("")
+                )))
+            }
+        });
+    }
+    if next < args.len() {
+        return Err(ErrString(format!(
+            "format: too many arguments, {} given but {} used",
+            args.len(),
+            next
+        )));
+    }
+    Ok(res)
+}
+
+fn printf(a: MalArgs) -> MalRet {
+    print!("{}", format_str(text(&a[0]), &a[1..])?);
+    let _ = io::stdout().flush();
+    Ok(Nil)
+}
+
+// Regular expressions. Functions taking a regex also take a string,
+// compiled once and cached by types::regex.
+
+fn pattern(mv: &MalVal) -> Result<Rc<::regex::Regex>, MalErr> {
+    match mv {
+        Regex(re) => Ok(re.clone()),
+        _ => regex(text(mv)),
+    }
+}
+
+// A match is the matched string, or a vector of it and its groups when
+// the regex has any; a group that took no part in the match is nil.
+fn match_val(caps: &Captures) -> MalVal {
+    if caps.len() == 1 {
+        Str(caps[0].to_string())
+    } else {
+        groups(caps)
+    }
+}
+
+fn groups(caps: &Captures) -> MalVal {
+    vector!(caps
+        .iter()
+        .map(|m| m.map_or(Nil, |m| Str(m.as_str().to_string())))
+        .collect::<MalArgs>())
+}
+
+fn replace_with(re: &::regex::Regex, s: &str, f: &MalVal) -> MalRet {
+    let mut res = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(s) {
+        let m = caps.get(0).unwrap();
+        res.push_str(&s[last..m.start()]);
+        res.push_str(&f.apply(vec![match_val(&caps)])?.pr_str(false));
+        last = m.end();
+    }
+    res.push_str(&s[last..]);
+    Ok(Str(res))
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
+    Ok(Regex(pattern(&a[0])?))
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    Ok(pattern(&a[0])?
+        .captures(text(&a[1]))
+        .map_or(Nil, |caps| match_val(&caps)))
+}
+
+fn re_matches(a: MalArgs) -> MalRet {
+    let re = regex(&format!(r"\A(?:{})\z", pattern(&a[0])?.as_str()))?;
+    Ok(re
+        .captures(text(&a[1]))
+        .map_or(Nil, |caps| match_val(&caps)))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let re = pattern(&a[0])?;
+    Ok(list!(re
+        .captures_iter(text(&a[1]))
+        .map(|caps| match_val(&caps))
+        .collect::<MalArgs>()))
+}
+
+// Unlike re-find, the groups are returned even if the regex has none.
+fn re_groups(a: MalArgs) -> MalRet {
+    Ok(pattern(&a[0])?
+        .captures(text(&a[1]))
+        .map_or(Nil, |caps| groups(&caps)))
+}
+
+// JSON. Objects read as maps and arrays as vectors; numbers read as ints
+// when they are integral and fit, and as floats otherwise.
+
+struct JsonParser<'a> {
+    src: &'a str,
+    pos: usize,
+    keywordize: bool,
+}
+
+impl<'a> JsonParser<'a> {
+    fn fail<T>(&self, what: &str) -> Result<T, MalErr> {
+        Err(ErrString(format!(
+            "json-parse: {} at offset {}",
+            what, self.pos
+        )))
+    }
+
+    fn unexpected<T>(&self) -> Result<T, MalErr> {
+        match self.peek() {
+            Some(c) => self.fail(&format!("unexpected {:?}", c)),
+            None => self.fail("unexpected end of input"),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> MalRet {
+        self.skip_ws();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Str(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => {
+                for (lit, val) in &[("null", Nil), ("true", Bool(true)), ("false", Bool(false))] {
+                    if self.src[self.pos..].starts_with(lit) {
+                        self.pos += lit.len();
+                        return Ok(val.clone());
+                    }
+                }
+                self.unexpected()
+            }
+        }
+    }
+
+    // Consumes the separator after an element, returning true at the
+    // closing bracket.
+    fn end_of(&mut self, close: char) -> Result<bool, MalErr> {
+        self.skip_ws();
+        match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                Ok(false)
+            }
+            Some(c) if c == close => {
+                self.pos += 1;
+                Ok(true)
+            }
+            _ => self.unexpected(),
+        }
+    }
+
+    fn array(&mut self) -> MalRet {
+        self.pos += 1;
+        let mut items = vec![];
+        self.skip_ws();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+        } else {
+            loop {
+                items.push(self.value()?);
+                if self.end_of(']')? {
+                    break;
+                }
+            }
+        }
+        Ok(vector!(items))
+    }
+
+    fn object(&mut self) -> MalRet {
+        self.pos += 1;
+        let mut hm = MalMap::default();
+        self.skip_ws();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Hash(hm, Rc::new(Nil)));
+        }
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('"') {
+                return self.unexpected();
+            }
+            let key = self.string()?;
+            self.skip_ws();
+            if self.peek() != Some(':') {
+                return self.unexpected();
+            }
+            self.pos += 1;
+            let key = if self.keywordize {
+                Str(format!("\u{29e}{}", key))
+            } else {
+                Str(key)
+            };
+            hm.insert(key, self.value()?);
+            if self.end_of('}')? {
+                return Ok(Hash(hm, Rc::new(Nil)));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, MalErr> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) if c < ' ' => return self.fail("unescaped control character in string"),
+                Some(c) => c,
+                None => return self.unexpected(),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = match self.peek() {
+                        Some(e) => e,
+                        None => return self.unexpected(),
+                    };
+                    s.push(match e {
+                        '"' | '\\' | '/' => e,
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            self.pos += 1;
+                            s.push(self.unicode_escape()?);
+                            continue;
+                        }
+                        _ => return self.fail(&format!("invalid escape \\{}", e)),
+                    });
+                    self.pos += e.len_utf8();
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, MalErr> {
+        match self.src.get(self.pos..self.pos + 4) {
+            Some(d) if d.chars().all(|c| c.is_ascii_hexdigit()) => {
+                self.pos += 4;
+                Ok(u32::from_str_radix(d, 16).unwrap())
+            }
+            _ => self.fail("invalid \\u escape"),
+        }
+    }
+
+    // Characters outside the BMP are written as a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char, MalErr> {
+        let mut code = self.hex4()?;
+        if (0xD800..0xDC00).contains(&code) && self.src[self.pos..].starts_with("\\u") {
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return self.fail("invalid \\u escape");
+            }
+            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+        }
+        match ::std::char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.fail("invalid \\u escape"),
+        }
+    }
+
+    fn number(&mut self) -> MalRet {
+        lazy_static! {
+            static ref NUM_RE: ::regex::Regex =
+                ::regex::Regex::new(r"^-?(?:0|[1-9][0-9]*)(\.[0-9]+)?([eE][-+]?[0-9]+)?").unwrap();
+        }
+        let (num, integral) = match NUM_RE.captures(&self.src[self.pos..]) {
+            Some(caps) => (
+                caps[0].to_string(),
+                caps.get(1).is_none() && caps.get(2).is_none(),
+            ),
+            None => return self.unexpected(),
+        };
+        if integral {
+            if let Ok(i) = num.parse() {
+                self.pos += num.len();
+                return Ok(Int(i));
+            }
+        }
+        match num.parse::<f64>() {
+            Ok(f) if f.is_finite() => {
+                self.pos += num.len();
+                Ok(Float(f))
+            }
+            _ => self.fail(&format!("number {} out of range", num)),
+        }
+    }
+}
+
+// (json-parse s :keywordize true) reads object keys as keywords.
+fn json_parse(a: MalArgs) -> MalRet {
+    let mut p = JsonParser {
+        src: text(&a[0]),
+        pos: 0,
+        keywordize: flag("json-parse", "keywordize", &a[1..])?,
+    };
+    let val = p.value()?;
+    p.skip_ws();
+    match p.peek() {
+        Some(_) => p.unexpected(),
+        None => Ok(val),
+    }
+}
+
+fn json_str(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Writes the elements of an array or object, one per line and indented
+// by depth when pretty printing.
+fn json_items<T, F>(
+    items: &[T],
+    brackets: (&str, &str),
+    depth: Option<usize>,
+    out: &mut String,
+    write: F,
+) -> Result<(), MalErr>
+where
+    F: Fn(&T, &mut String) -> Result<(), MalErr>,
+{
+    out.push_str(brackets.0);
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if let Some(d) = depth {
+            out.push('\n');
+            out.push_str(&"  ".repeat(d + 1));
+        }
+        write(item, out)?;
+    }
+    if let (Some(d), false) = (depth, items.is_empty()) {
+        out.push('\n');
+        out.push_str(&"  ".repeat(d));
+    }
+    out.push_str(brackets.1);
+    Ok(())
+}
+
+// Keywords are written as strings and seqs as arrays. Object keys are
+// written in insertion order, or in key order for a sorted map.
+fn json_write(mv: &MalVal, depth: Option<usize>, out: &mut String) -> Result<(), MalErr> {
+    let inner = depth.map(|d| d + 1);
+    match mv {
+        Nil => out.push_str("null"),
+        Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Int(i) => out.push_str(&i.to_string()),
+        Float(f) if f.is_finite() => out.push_str(&format!("{:?}", f)),
+        Str(s) if mv.keyword_q() => json_str(&s[2..], out),
+        Str(s) => json_str(s, out),
+        List(..) | Vector(..) | LazySeq(..) => {
+            json_items(&mv.seq_vec()?, ("[", "]"), depth, out, |item, out| {
+                json_write(item, inner, out)
+            })?
+        }
+        Hash(..) | SortedMap(..) => {
+            let hm = mv.map_entries().unwrap();
+            let mut entries = vec![];
+            for (k, v) in hm.iter() {
+                match k {
+                    Str(s) if k.keyword_q() => entries.push((&s[2..], v)),
+                    Str(s) => entries.push((&s[..], v)),
+                    _ => {
+                        return Err(ErrString(format!(
+                            "json-stringify: map keys must be strings or keywords, got {}",
+                            k.pr_str(true)
+                        )))
+                    }
+                }
+            }
+            json_items(&entries, ("{", "}"), depth, out, |(k, v), out| {
+                json_str(k, out);
+                out.push_str(if depth.is_some() { ": " } else { ":" });
+                json_write(v, inner, out)
+            })?
+        }
+        Float(f) => {
+            return Err(ErrString(format!(
+                "json-stringify: {:?} has no JSON representation",
+                f
+            )))
+        }
+        _ => {
+            return Err(ErrString(format!(
+                "json-stringify: {} has no JSON representation",
+                ty_names(ty_of(mv)).join(" or ")
+            )))
+        }
+    }
+    Ok(())
+}
+
+// (json-stringify v :pretty true) indents nested values by two spaces.
+fn json_stringify(a: MalArgs) -> MalRet {
+    let depth = if flag("json-stringify", "pretty", &a[1..])? {
+        Some(0)
+    } else {
+        None
+    };
+    let mut out = String::new();
+    json_write(&a[0], depth, &mut out)?;
+    Ok(Str(out))
+}
+
+// Builtins declare their name, the range of argument counts they accept
+// and the types allowed for each parameter. Arguments are validated
+// against the signature before the body runs, so bodies may index args
+// freely, and the signature is exposed as the meta of the function.
+
+type Ty = u16;
+
+const NIL: Ty = 1 << 0;
+const BOOL: Ty = 1 << 1;
+const INT: Ty = 1 << 2;
+const STRING: Ty = 1 << 3;
+const KEYWORD: Ty = 1 << 4;
+const SYMBOL: Ty = 1 << 5;
+const LIST: Ty = 1 << 6;
+const VECTOR: Ty = 1 << 7;
+const MAP: Ty = 1 << 8;
+const FN: Ty = 1 << 9;
+const ATOM: Ty = 1 << 10;
+const LAZY: Ty = 1 << 11;
+const REGEX: Ty = 1 << 12;
+const READER: Ty = 1 << 13;
+const FLOAT: Ty = 1 << 14;
+const SET: Ty = 1 << 15;
+const SEQ: Ty = LIST | VECTOR | LAZY;
+const COLL: Ty = SEQ | NIL | STRING | MAP | SET;
+const NUMBER: Ty = INT | FLOAT;
+const ANY: Ty = !0;
+
+const MANY: usize = usize::MAX;
+
+const TY_NAMES: [(Ty, &str); 16] = [
+    (NIL, "nil"),
+    (BOOL, "boolean"),
+    (INT, "int"),
+    (STRING, "string"),
+    (KEYWORD, "keyword"),
+    (SYMBOL, "symbol"),
+    (LIST, "list"),
+    (VECTOR, "vector"),
+    (MAP, "map"),
+    (FN, "function"),
+    (ATOM, "atom"),
+    (LAZY, "lazy-seq"),
+    (REGEX, "regex"),
+    (READER, "reader"),
+    (FLOAT, "float"),
+    (SET, "set"),
+];
+
+fn ty_of(mv: &MalVal) -> Ty {
+    match mv {
+        Nil => NIL,
+        Bool(_) => BOOL,
+        Int(_) => INT,
+        Float(_) => FLOAT,
+        Str(_) if mv.keyword_q() => KEYWORD,
+        Str(_) => STRING,
+        Sym(_) => SYMBOL,
+        List(_, _) => LIST,
+        Vector(_, _) => VECTOR,
+        Hash(_, _) | SortedMap(_, _) => MAP,
+        Set(_, _) | SortedSet(_, _) => SET,
+        Func(_, _) | MalFunc { .. } => FN,
+        Atom(_) => ATOM,
+        LazySeq(_, _) => LAZY,
+        Regex(_) => REGEX,
+        Reader(_) => READER,
+    }
+}
+
+// Keywords, maps, sets and vectors can be called, so they are accepted
+// where a function is expected.
+const CALLABLE: Ty = KEYWORD | MAP | SET | VECTOR;
+
+fn ty_names(ty: Ty) -> Vec<&'static str> {
+    if ty == ANY {
+        return vec!["any"];
+    }
+    TY_NAMES
+        .iter()
+        .filter(|(t, _)| ty & t != 0)
+        .map(|(_, n)| *n)
+        .collect()
+}
+
+// The type of a parameter past the end of params is that of the last one.
+fn check_args(
+    name: &str,
+    min: usize,
+    max: usize,
+    params: &[Ty],
+    a: &MalArgs,
+) -> Result<(), MalErr> {
+    if a.len() < min || a.len() > max {
+        let expected = match (min, max) {
+            (min, MANY) => format!("at least {}", min),
+            (min, max) if min == max => format!("{}", min),
+            (min, max) => format!("{} to {}", min, max),
+        };
+        return Err(ErrString(format!(
+            "{}: wrong number of args ({}), expected {}",
+            name,
+            a.len(),
+            expected
+        )));
+    }
+    for (i, arg) in a.iter().enumerate() {
+        let ty = params[i.min(params.len() - 1)];
+        let accepted = if ty & FN != 0 { ty | CALLABLE } else { ty };
+        if accepted & ty_of(arg) == 0 {
+            return Err(ErrString(format!(
+                "{}: argument {} must be {}, got {}",
+                name,
+                i + 1,
+                ty_names(ty).join(" or "),
+                ty_names(ty_of(arg)).join("")
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn signature(name: &str, min: usize, max: usize, params: &[Ty]) -> MalVal {
+    let kw = |s: &str| Str(format!("\u{29e}{}", s));
+    let types = params
+        .iter()
+        .map(|ty| vector!(ty_names(*ty).into_iter().map(kw).collect::<MalArgs>()))
+        .collect::<MalArgs>();
+    let max = if max == MANY { Nil } else { Int(max as i64) };
+    hash_map(vec![
+        kw("name"),
+        Str(name.to_string()),
+        kw("min-args"),
+        Int(min as i64),
+        kw("max-args"),
+        max,
+        kw("params"),
+        vector!(types),
+    ])
+    .unwrap_or(Nil)
+}
+
+macro_rules! builtins {
+    ($(($name:expr, $min:expr, $max:expr, [$($ty:expr),*], $fn:expr)),* $(,)*) => {{
+        vec![$((
+            $name,
+            Func(
+                |a: MalArgs| {
+                    check_args($name, $min, $max, &[$($ty),*], &a)?;
+                    $fn(a)
+                },
+                Rc::new(signature($name, $min, $max, &[$($ty),*])),
+            ),
+        )),*]
+    }};
+}
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+    builtins![
+        ("=", 2, 2, [ANY, ANY], |a: MalArgs| Ok(Bool(a[0] == a[1]))),
+        ("compare", 2, 2, [ANY, ANY], |a: MalArgs| {
+            Ok(Int(compare(&a[0], &a[1])? as i64))
+        }),
+        ("hash", 1, 1, [ANY], hash),
+        ("throw", 1, 1, [ANY], |a: MalArgs| {
+            signal_error(&a[0])?;
+            Err(ErrMalVal(a[0].clone()))
+        }),
+        ("signal", 1, 1, [ANY], |a: MalArgs| signal(&a[0])),
+        (
+            "invoke-restart",
+            1,
+            MANY,
+            [SYMBOL | STRING, ANY],
+            invoke_restart
+        ),
+        ("compute-restarts", 0, 0, [], compute_restarts),
+        ("nil?", 1, 1, [ANY], fn_is_type!(Nil)),
+        ("true?", 1, 1, [ANY], fn_is_type!(Bool(true))),
+        ("false?", 1, 1, [ANY], fn_is_type!(Bool(false))),
+        ("symbol", 1, 1, [STRING], symbol),
+        ("symbol?", 1, 1, [ANY], fn_is_type!(Sym(_))),
         (
             "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
+            1,
+            1,
+            [ANY],
+            fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))
         ),
-        ("keyword", func(|a| a[0].keyword())),
+        ("keyword", 1, 1, [STRING | KEYWORD], |a: MalArgs| a[0]
+            .keyword()),
         (
             "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
+            1,
+            1,
+            [ANY],
+            fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("number?", 1, 1, [ANY], fn_is_type!(Int(_), Float(_))),
+        ("float?", 1, 1, [ANY], fn_is_type!(Float(_))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            1,
+            1,
+            [ANY],
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            1,
+            1,
+            [ANY],
+            fn_is_type!(MalFunc{is_macro,..} if is_macro)
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        ("pr-str", 0, MANY, [ANY], |a: MalArgs| {
+            realize_all(&a)?;
+            Ok(Str(pr_seq(&a, true, "", "", " ")))
+        }),
+        ("str", 0, MANY, [ANY], |a: MalArgs| {
+            realize_all(&a)?;
+            Ok(Str(pr_seq(&a, false, "", "", "")))
+        }),
+        ("prn", 0, MANY, [ANY], |a: MalArgs| {
+            realize_all(&a)?;
+            println!("{}", pr_seq(&a, true, "", "", " "));
+            Ok(Nil)
+        }),
+        ("println", 0, MANY, [ANY], |a: MalArgs| {
+            realize_all(&a)?;
+            println!("{}", pr_seq(&a, false, "", "", " "));
+            Ok(Nil)
+        }),
+        ("format", 1, MANY, [STRING, ANY], |a: MalArgs| {
+            Ok(Str(format_str(text(&a[0]), &a[1..])?))
+        }),
+        ("printf", 1, MANY, [STRING, ANY], printf),
+        ("read-string", 1, 3, [STRING, KEYWORD, ANY], |a: MalArgs| {
+            if flag("read-string", "edn", &a[1..])? {
+                read_edn(text(&a[0]))
+            } else {
+                read_str(text(&a[0]).to_string())
+            }
+        }),
+        ("edn-read", 1, 1, [STRING], |a: MalArgs| read_edn(text(
+            &a[0]
+        ))),
+        ("edn-write", 1, 1, [ANY], |a: MalArgs| {
+            a[0].realize()?;
+            Ok(Str(a[0].edn_str()?))
+        }),
         (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
+            "edn-register-tag",
+            2,
+            2,
+            [SYMBOL | STRING, FN | NIL],
+            |a: MalArgs| {
+                let tag = match a[0] {
+                    Sym(ref s) | Str(ref s) => s.clone(),
+                    _ => return error("edn-register-tag: tag must be a symbol or string"),
+                };
+                register_tag(&tag, a[1].clone());
                 Ok(Nil)
-            }),
+            }
         ),
+        ("readline", 1, 1, [STRING], readline),
+        ("slurp", 1, 1, [STRING], fn_str!(|f| { slurp(f) })),
+        ("spit", 2, 4, [STRING, ANY, KEYWORD, ANY], spit),
+        ("file-exists?", 1, 1, [STRING], |a: MalArgs| {
+            Ok(Bool(fs::metadata(text(&a[0])).is_ok()))
+        }),
+        ("directory?", 1, 1, [STRING], |a: MalArgs| {
+            Ok(Bool(fs::metadata(text(&a[0])).is_ok_and(|m| m.is_dir())))
+        }),
+        ("list-dir", 1, 1, [STRING], list_dir),
+        ("mkdir", 1, 1, [STRING], |a: MalArgs| {
+            let path = text(&a[0]);
+            fs::create_dir_all(path)
+                .map(|_| Nil)
+                .map_err(|e| io_error("mkdir", path, e))
+        }),
+        ("delete-file", 1, 1, [STRING], delete_file),
+        ("rename-file", 2, 2, [STRING, STRING], |a: MalArgs| {
+            let (from, to) = (text(&a[0]), text(&a[1]));
+            fs::rename(from, to)
+                .map(|_| Nil)
+                .map_err(|e| io_error("rename-file", from, e))
+        }),
+        ("file-size", 1, 1, [STRING], |a: MalArgs| {
+            Ok(Int(metadata("file-size", text(&a[0]))?.len() as i64))
+        }),
+        ("file-mtime", 1, 1, [STRING], file_mtime),
+        ("open-reader", 1, 1, [STRING], |a: MalArgs| {
+            Ok(Reader(open_reader("open-reader", text(&a[0]))?))
+        }),
+        ("read-line", 1, 1, [READER], read_line),
+        ("read-lines", 1, 1, [STRING | READER], read_lines),
+        ("close", 1, 1, [READER], |a: MalArgs| {
+            if let Reader(ref r) = a[0] {
+                r.borrow_mut().lines = None;
+            }
+            Ok(Nil)
+        }),
+        ("<", 2, 2, [NUMBER, NUMBER], fn_compare!(<)),
+        ("<=", 2, 2, [NUMBER, NUMBER], fn_compare!(<=)),
+        (">", 2, 2, [NUMBER, NUMBER], fn_compare!(>)),
+        (">=", 2, 2, [NUMBER, NUMBER], fn_compare!(>=)),
         (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            "+",
+            2,
+            2,
+            [NUMBER, NUMBER],
+            fn_arith!("+", checked_add, |x, y| x + y)
+        ),
+        (
+            "-",
+            2,
+            2,
+            [NUMBER, NUMBER],
+            fn_arith!("-", checked_sub, |x, y| x - y)
+        ),
+        (
+            "*",
+            2,
+            2,
+            [NUMBER, NUMBER],
+            fn_arith!("*", checked_mul, |x, y| x * y)
+        ),
+        ("/", 2, 2, [NUMBER, NUMBER], |a: MalArgs| {
+            if let (Int(_), Int(0)) = (&a[0], &a[1]) {
+                return error("/: divide by zero");
+            }
+            fn_arith!("/", checked_div, |x, y| x / y)(a)
+        }),
+        ("time-ms", 0, 0, [], time_ms),
+        ("getenv", 1, 1, [STRING], |a: MalArgs| {
+            Ok(env::var(text(&a[0])).map_or(Nil, Str))
+        }),
+        ("setenv", 2, 2, [STRING, STRING | NIL], |a: MalArgs| {
+            match a[1] {
+                Nil => env::remove_var(text(&a[0])),
+                _ => env::set_var(text(&a[0]), text(&a[1])),
+            }
+            Ok(Nil)
+        }),
+        ("exit", 0, 1, [INT], exit),
+        ("cwd", 0, 0, [], |_a: MalArgs| match env::current_dir() {
+            Ok(p) => Ok(Str(p.to_string_lossy().into_owned())),
+            Err(e) => error(&format!("cwd: {}", e)),
+        }),
+        ("chdir", 1, 1, [STRING], chdir),
+        ("sh", 1, MANY, [STRING, ANY], sh),
+        (
+            "sequential?",
+            1,
+            1,
+            [ANY],
+            fn_is_type!(List(_, _), Vector(_, _), LazySeq(_, _))
+        ),
+        ("list", 0, MANY, [ANY], |a: MalArgs| Ok(list!(a))),
+        ("list?", 1, 1, [ANY], fn_is_type!(List(_, _))),
+        ("vector", 0, MANY, [ANY], |a: MalArgs| Ok(vector!(a))),
+        ("vector?", 1, 1, [ANY], fn_is_type!(Vector(_, _))),
+        ("hash-map", 0, MANY, [ANY], |a: MalArgs| hash_map(a)),
+        (
+            "map?",
+            1,
+            1,
+            [ANY],
+            fn_is_type!(Hash(_, _), SortedMap(_, _))
+        ),
+        ("set?", 1, 1, [ANY], fn_is_type!(Set(_, _), SortedSet(_, _))),
+        (
+            "sorted?",
+            1,
+            1,
+            [ANY],
+            fn_is_type!(SortedMap(_, _), SortedSet(_, _))
+        ),
+        ("assoc", 1, MANY, [MAP, ANY], assoc),
+        ("dissoc", 1, MANY, [MAP, ANY], dissoc),
+        ("get", 2, 2, [MAP | SET | NIL, ANY], get),
+        ("contains?", 2, 2, [MAP | SET, ANY], contains_q),
+        ("keys", 1, 1, [MAP], keys),
+        ("vals", 1, 1, [MAP], vals),
+        ("sorted-map", 0, MANY, [ANY], |a: MalArgs| {
+            Ok(SortedMap(sorted_assoc(sorted(&Nil), &a)?, Rc::new(Nil)))
+        }),
+        ("sorted-map-by", 1, MANY, [FN, ANY], |a: MalArgs| {
+            Ok(SortedMap(
+                sorted_assoc(sorted(&a[0]), &a[1..])?,
+                Rc::new(Nil),
+            ))
+        }),
+        ("sorted-set", 0, MANY, [ANY], |a: MalArgs| {
+            Ok(SortedSet(sorted_conj(sorted(&Nil), &a)?, Rc::new(Nil)))
+        }),
+        ("sorted-set-by", 1, MANY, [FN, ANY], |a: MalArgs| {
+            Ok(SortedSet(
+                sorted_conj(sorted(&a[0]), &a[1..])?,
+                Rc::new(Nil),
+            ))
+        }),
+        ("subseq", 3, 5, [MAP | SET, FN, ANY], |a: MalArgs| {
+            Ok(list!(subseq("subseq", &a)?))
+        }),
+        ("rsubseq", 3, 5, [MAP | SET, FN, ANY], |a: MalArgs| {
+            let mut items = subseq("rsubseq", &a)?;
+            items.reverse();
+            Ok(list!(items))
+        }),
+        ("hash-set", 0, MANY, [ANY], |a: MalArgs| {
+            Ok(Set(a.into_iter().collect(), Rc::new(Nil)))
+        }),
+        ("set", 1, 1, [COLL], set),
+        ("disj", 1, MANY, [SET | NIL, ANY], disj),
+        ("union", 0, MANY, [SET | NIL], union),
+        ("intersection", 1, MANY, [SET | NIL], intersection),
+        ("difference", 1, MANY, [SET | NIL], difference),
+        ("subset?", 2, 2, [SET | NIL, SET | NIL], |a: MalArgs| {
+            let b = elems(&a[1]);
+            Ok(Bool(elems(&a[0]).iter().all(|mv| b.contains(mv))))
+        }),
+        ("vec", 1, 1, [SEQ | SET], vec),
+        ("cons", 2, 2, [ANY, SEQ], cons),
+        ("concat", 0, MANY, [SEQ], concat),
+        ("empty?", 1, 1, [SEQ | MAP | SET | NIL], |a: MalArgs| a[0]
+            .empty_q()),
+        ("nth", 2, 2, [SEQ, INT], nth),
+        ("first", 1, 1, [SEQ | MAP | SET | NIL], first),
+        ("rest", 1, 1, [SEQ | MAP | SET | NIL], rest),
+        ("count", 1, 1, [SEQ | MAP | SET | NIL], |a: MalArgs| a[0]
+            .count()),
+        ("apply", 2, MANY, [FN, ANY], apply),
+        ("map", 2, MANY, [FN, COLL], map),
+        ("conj", 1, MANY, [SEQ | SET, ANY], conj),
+        ("seq", 1, 1, [SEQ | MAP | SET | STRING | NIL], seq),
+        ("filter", 2, 2, [FN, COLL], filter),
+        ("remove", 2, 2, [FN, COLL], remove),
+        ("mapcat", 2, MANY, [FN, COLL], mapcat),
+        ("reduce", 2, 3, [FN, ANY, COLL], reduce),
+        ("some", 2, 2, [FN, COLL], some),
+        ("every?", 2, 2, [FN, COLL], every_q),
+        ("sort", 1, 2, [ANY, COLL], sort),
+        ("sort-by", 2, 3, [FN, ANY, COLL], sort_by),
+        ("max-key", 2, MANY, [FN, ANY], |a: MalArgs| {
+            extreme_key(&a, Ordering::Greater)
+        }),
+        ("min-key", 2, MANY, [FN, ANY], |a: MalArgs| {
+            extreme_key(&a, Ordering::Less)
+        }),
+        ("group-by", 2, 2, [FN, COLL], group_by),
+        ("frequencies", 1, 1, [COLL], frequencies),
+        ("partition", 2, 4, [INT, ANY, ANY, COLL], partition),
+        ("interleave", 0, MANY, [COLL], interleave),
+        ("distinct", 1, 1, [COLL], distinct),
+        ("reverse", 1, 1, [COLL], reverse),
+        ("last", 1, 1, [COLL], last),
+        ("butlast", 1, 1, [COLL], butlast),
+        ("subs", 2, 3, [STRING, INT], subs),
+        ("split", 2, 2, [STRING, STRING | REGEX], split),
+        ("join", 1, 2, [COLL, COLL], join),
+        ("trim", 1, 1, [STRING], |a: MalArgs| Ok(Str(text(&a[0])
+            .trim()
+            .to_string()))),
+        ("triml", 1, 1, [STRING], |a: MalArgs| Ok(Str(text(&a[0])
+            .trim_start()
+            .to_string()))),
+        ("trimr", 1, 1, [STRING], |a: MalArgs| Ok(Str(text(&a[0])
+            .trim_end()
+            .to_string()))),
+        ("upper-case", 1, 1, [STRING], |a: MalArgs| Ok(Str(text(
+            &a[0]
+        )
+        .to_uppercase()))),
+        ("lower-case", 1, 1, [STRING], |a: MalArgs| Ok(Str(text(
+            &a[0]
+        )
+        .to_lowercase()))),
+        ("starts-with?", 2, 2, [STRING], |a: MalArgs| Ok(Bool(
+            text(&a[0]).starts_with(text(&a[1]))
+        ))),
+        ("ends-with?", 2, 2, [STRING], |a: MalArgs| Ok(Bool(
+            text(&a[0]).ends_with(text(&a[1]))
+        ))),
+        ("includes?", 2, 2, [STRING], |a: MalArgs| Ok(Bool(
+            text(&a[0]).contains(text(&a[1]))
+        ))),
+        ("index-of", 2, 3, [STRING, STRING, INT], index_of),
+        (
+            "replace",
+            3,
+            3,
+            [STRING, STRING | REGEX, STRING | FN],
+            replace
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        ("string-reverse", 1, 1, [STRING], |a: MalArgs| Ok(Str(
+            text(&a[0]).graphemes(true).rev().collect()
+        ))),
+        ("char-code", 1, 1, [STRING], char_code),
+        ("from-char-code", 1, 1, [INT], from_char_code),
+        ("re-pattern", 1, 1, [STRING | REGEX], re_pattern),
+        ("re-find", 2, 2, [REGEX | STRING, STRING], re_find),
+        ("re-matches", 2, 2, [REGEX | STRING, STRING], re_matches),
+        ("re-seq", 2, 2, [REGEX | STRING, STRING], re_seq),
+        ("json-parse", 1, 3, [STRING, KEYWORD, ANY], json_parse),
+        ("json-stringify", 1, 3, [ANY, KEYWORD, ANY], json_stringify),
+        ("re-groups", 2, 2, [REGEX | STRING, STRING], re_groups),
+        ("range", 0, 3, [INT], range),
+        ("iterate", 2, 2, [FN, ANY], iterate),
+        ("repeat", 1, 2, [ANY], repeat),
+        ("cycle", 1, 1, [SEQ | NIL], cycle),
+        ("take", 2, 2, [INT, SEQ | NIL], |a: MalArgs| Ok(lazy_take(
+            a[0].clone(),
+            a[1].clone()
+        ))),
+        ("drop", 2, 2, [INT, SEQ | NIL], |a: MalArgs| Ok(lazy_drop(
+            a[0].clone(),
+            a[1].clone()
+        ))),
+        ("take-while", 2, 2, [FN, SEQ | NIL], |a: MalArgs| Ok(
+            lazy_take_while(a[0].clone(), a[1].clone())
+        )),
+        ("meta", 1, 1, [ANY], |a: MalArgs| a[0].get_meta()),
+        ("with-meta", 2, 2, [ANY, ANY], |a: MalArgs| a[0]
+            .clone()
+            .with_meta(&a[1])),
+        ("atom", 1, 1, [ANY], |a: MalArgs| Ok(atom(&a[0]))),
+        ("atom?", 1, 1, [ANY], fn_is_type!(Atom(_))),
+        ("deref", 1, 1, [ATOM], |a: MalArgs| a[0].deref()),
+        ("reset!", 2, 2, [ATOM, ANY], |a: MalArgs| a[0]
+            .reset_bang(&a[1])),
+        ("swap!", 2, MANY, [ATOM, FN, ANY], |a: MalArgs| a[0]
+            .swap_bang(&a[1..].to_vec())),
     ]
 }
@@ -3,9 +3,10 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::condition::{restart_case, signal_error, Outcome};
+use crate::types::MalErr::{ErrRecur, ErrString};
+use crate::types::MalVal::{Hash, List, Nil, Str, Sym, Vector};
+use crate::types::{error, hash_map, MalArgs, MalErr, MalMap, MalRet, MalVal};
 
 #[derive(Debug)]
 pub struct EnvStruct {
@@ -15,6 +16,10 @@ pub struct EnvStruct {
 
 pub type Env = Rc<EnvStruct>;
 
+// The eval of the step that is binding, for the :or defaults of map
+// binding forms.
+pub type EvalFn = fn(MalVal, Env) -> MalRet;
+
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
@@ -26,25 +31,209 @@ pub fn env_new(outer: Option<Env>) -> Env {
 }
 
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+pub fn env_bind(
+    outer: Option<Env>,
+    mbinds: MalVal,
+    exprs: Vec<MalVal>,
+    eval: EvalFn,
+) -> Result<Env, MalErr> {
     let env = env_new(outer);
-    match mbinds {
-        List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
-                    }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
-                    }
+    let binds: MalArgs = match mbinds.seq_iter() {
+        Some(binds) => binds.cloned().collect(),
+        None => return Err(ErrString("env_bind binds not List/Vector".to_string())),
+    };
+    let variadic = binds.iter().position(|b| match b {
+        Sym(s) => s == "&",
+        _ => false,
+    });
+    match variadic {
+        Some(i) if binds.len() != i + 2 => {
+            return Err(ErrString(
+                "& must be followed by exactly one binding form".to_string(),
+            ));
+        }
+        Some(i) if exprs.len() < i => {
+            return Err(ErrString(format!(
+                "wrong number of args ({}), expected at least {}",
+                exprs.len(),
+                i
+            )));
+        }
+        None if exprs.len() != binds.len() => {
+            return Err(ErrString(format!(
+                "wrong number of args ({}), expected {}",
+                exprs.len(),
+                binds.len()
+            )));
+        }
+        _ => (),
+    }
+    for (i, b) in binds.iter().enumerate() {
+        match b {
+            Sym(s) if s == "&" => {
+                env_destructure(&env, &binds[i + 1], list!(exprs[i..].to_vec()), eval)?;
+                break;
+            }
+            _ => env_destructure(&env, b, exprs[i].clone(), eval)?,
+        }
+    }
+    Ok(env)
+}
+
+// Bind the symbols in a binding form to the matching parts of val. A form
+// is a symbol, a vector destructuring a sequence as [a b & rest :as all]
+// or a map destructuring an associative value as
+// {a :k :keys [x y] :strs [s] :syms [t] :or {y 0} :as m}. Forms nest;
+// missing elements and keys bind nil, or for a key the value of its :or
+// default, evaluated in env.
+pub fn env_destructure(env: &Env, form: &MalVal, val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    match form {
+        Sym(s) if s == "&" => Err(ErrString(
+            "& is only allowed in a sequential binding form".to_string(),
+        )),
+        Sym(_) => env_set(env, form.clone(), val).map(|_| ()),
+        Vector(v, _) => destructure_seq(env, &v.iter().cloned().collect::<MalArgs>(), val, eval),
+        Hash(hm, _) => destructure_map(env, hm, val, eval),
+        _ => Err(ErrString(format!(
+            "invalid binding form: {}",
+            form.pr_str(true)
+        ))),
+    }
+}
+
+fn kw_q(mv: &MalVal, name: &str) -> bool {
+    match mv {
+        Str(s) => mv.keyword_q() && s[2..] == *name,
+        _ => false,
+    }
+}
+
+fn destructure_seq(env: &Env, forms: &[MalVal], val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    if !(val.sequential_q() || val == Nil) {
+        return Err(ErrString(format!(
+            "cannot destructure {} as a sequence",
+            val.pr_str(true)
+        )));
+    }
+    let (forms, all) = match forms.len() {
+        n if n >= 2 && kw_q(&forms[n - 2], "as") => (&forms[..n - 2], Some(&forms[n - 1])),
+        _ => (forms, None),
+    };
+    let mut seq = val.clone();
+    for (i, f) in forms.iter().enumerate() {
+        if kw_q(f, "as") {
+            return Err(ErrString(
+                ":as must be followed by exactly one symbol, at the end".to_string(),
+            ));
+        }
+        if let Sym(s) = f {
+            if s == "&" {
+                if forms.len() != i + 2 {
+                    return Err(ErrString(
+                        "& must be followed by exactly one binding form".to_string(),
+                    ));
                 }
+                let rest = match seq {
+                    Nil => list![],
+                    Vector(v, _) => list!(v.iter().cloned().collect::<MalArgs>()),
+                    seq => seq,
+                };
+                bind_as(env, all, val, eval)?;
+                return env_destructure(env, &forms[i + 1], rest, eval);
             }
-            Ok(env)
         }
-        _ => Err(ErrString("env_bind binds not List/Vector".to_string())),
+        let (first, rest) = seq.uncons()?.unwrap_or((Nil, Nil));
+        env_destructure(env, f, first, eval)?;
+        seq = rest;
     }
+    bind_as(env, all, val, eval)
+}
+
+fn bind_as(env: &Env, all: Option<&MalVal>, val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    match all {
+        Some(s @ Sym(_)) => env_destructure(env, s, val, eval),
+        Some(_) => Err(ErrString(
+            ":as must be followed by exactly one symbol, at the end".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn destructure_map(env: &Env, forms: &MalMap, val: MalVal, eval: EvalFn) -> Result<(), MalErr> {
+    let val = match val {
+        // (fn* [& {:keys [a b]}] ..) takes keyword arguments
+        ref v if v.sequential_q() => {
+            let kvs = v.seq_vec()?;
+            if !kvs.len().is_multiple_of(2) {
+                return Err(ErrString(format!(
+                    "cannot destructure {} as a map: odd number of elements",
+                    v.pr_str(true)
+                )));
+            }
+            hash_map(kvs)?
+        }
+        v @ Nil | v @ Hash(..) => v,
+        ref v if v.map_entries().is_some() => v.clone(),
+        v => {
+            return Err(ErrString(format!(
+                "cannot destructure {} as a map",
+                v.pr_str(true)
+            )))
+        }
+    };
+    let defaults = match forms.iter().find(|(k, _)| kw_q(k, "or")) {
+        Some((_, Hash(hm, _))) => hm.clone(),
+        Some(_) => return Err(ErrString(":or must be followed by a map".to_string())),
+        None => MalMap::default(),
+    };
+    let lookup = |form: &MalVal, key: &MalVal| -> MalRet {
+        match (val.lookup(key)?, defaults.get(form)) {
+            (Some(v), _) => Ok(v),
+            (None, Some(d)) => match eval(d.clone(), env.clone()) {
+                // a default is not in tail position
+                Err(ErrRecur(..)) => error("recur must be in tail position of a loop* or fn*"),
+                res => res,
+            },
+            (None, None) => Ok(Nil),
+        }
+    };
+    for (k, v) in forms.iter() {
+        let key_fn: fn(&str) -> MalVal = match k {
+            _ if kw_q(k, "keys") => |s| Str(format!("\u{29e}{}", s)),
+            _ if kw_q(k, "strs") => |s| Str(s.to_string()),
+            _ if kw_q(k, "syms") => |s| Sym(s.to_string()),
+            _ if kw_q(k, "as") => {
+                bind_as(env, Some(v), val.clone(), eval)?;
+                continue;
+            }
+            _ if kw_q(k, "or") => continue,
+            _ => {
+                env_destructure(env, k, lookup(k, v)?, eval)?;
+                continue;
+            }
+        };
+        let names = match v {
+            Vector(names, _) => names,
+            _ => {
+                return Err(ErrString(format!(
+                    "{} must be followed by a vector of symbols",
+                    k.pr_str(true)
+                )))
+            }
+        };
+        for name in names.iter() {
+            match name {
+                Sym(s) => env_set(env, name.clone(), lookup(name, &key_fn(s))?)?,
+                _ => {
+                    return Err(ErrString(format!(
+                        "{} must be followed by a vector of symbols",
+                        k.pr_str(true)
+                    )))
+                }
+            };
+        }
+    }
+    Ok(())
 }
 
 pub fn env_find(env: &Env, key: &str) -> Option<Env> {
@@ -55,6 +244,17 @@ pub fn env_find(env: &Env, key: &str) -> Option<Env> {
     }
 }
 
+// Every name bound in env or the envs it is nested in, for REPL
+// completion.
+#[allow(dead_code)]
+pub fn env_names(env: &Env) -> Vec<String> {
+    let mut names: Vec<String> = env.data.borrow().keys().cloned().collect();
+    if let Some(ref o) = env.outer {
+        names.extend(env_names(o));
+    }
+    names
+}
+
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
         Sym(ref s) => match env_find(env, s) {
@@ -64,12 +264,38 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
                 .get(s)
                 .ok_or(ErrString(format!("'{}' not found", s)))?
                 .clone()),
-            _ => error(&format!("'{}' not found", s)),
+            _ => unbound(env, key, &format!("'{}' not found", s)),
         },
         _ => error("Env.get called with non-Str"),
     }
 }
 
+// Signal an unbound symbol with use-value and store-value restarts in
+// effect, so a handler can supply a value instead of unwinding.
+fn unbound(env: &Env, key: &MalVal, msg: &str) -> MalRet {
+    let restarts = ["use-value".to_string(), "store-value".to_string()];
+    let outcome = restart_case(&restarts, || {
+        signal_error(&Str(msg.to_string()))?;
+        error(msg)
+    })?;
+    match outcome {
+        Outcome::Done(v) => Ok(v),
+        Outcome::Restart(i, args) => {
+            if args.len() != 1 {
+                return error(&format!("{}: expected 1 argument", restarts[i]));
+            }
+            if i == 1 {
+                let mut root = env.clone();
+                while let Some(ref o) = root.clone().outer {
+                    root = o.clone();
+                }
+                env_set(&root, key.clone(), args[0].clone())?;
+            }
+            Ok(args[0].clone())
+        }
+    }
+}
+
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(ref s) => {
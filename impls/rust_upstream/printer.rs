@@ -1,5 +1,9 @@
+use crate::types::MalErr::{self, ErrString};
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, LazySeq, List, MalFunc, Nil, Reader, Regex, Set, SortedMap,
+    SortedSet, Str, Sym, Vector,
+};
 
 fn escape_str(s: &str) -> String {
     s.chars()
@@ -20,7 +24,7 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            Float(f) => format!("{:?}", f),
             Str(s) => {
                 if s.starts_with("\u{29e}") {
                     format!(":{}", &s[2..])
@@ -31,31 +35,92 @@ impl MalVal {
                 }
             }
             Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            List(l, _) => pr_seq(l, print_readably, "(", ")", " "),
+            Vector(l, _) => pr_seq(l, print_readably, "[", "]", " "),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
+            Set(hs, _) => pr_seq(hs, print_readably, "#{", "}", " "),
+            SortedMap(s, _) => pr_seq(
+                s.entries.iter().flat_map(|(k, v)| vec![k, v]),
+                print_readably,
+                "{",
+                "}",
+                " ",
+            ),
+            SortedSet(s, _) => pr_seq(
+                s.entries.iter().map(|e| &e.0),
+                print_readably,
+                "#{",
+                "}",
+                " ",
+            ),
             Func(f, _) => format!("#<fn {:?}>", f),
+            MalFunc {
+                ast: a, params: p, ..
+            } if **p == Nil && a.seq_iter().is_some() => {
+                pr_seq(a.seq_iter().unwrap(), true, "(fn* ", ")", " ")
+            }
             MalFunc {
                 ast: a, params: p, ..
             } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            Regex(re) if print_readably => format!("#\"{}\"", re.as_str().replace('"', "\\\"")),
+            Regex(re) => re.as_str().to_string(),
+            Reader(r) => format!("#<reader {:?}>", r.borrow().path),
+            LazySeq(..) => pr_seq(
+                &self.seq_vec().unwrap_or_default(),
+                print_readably,
+                "(",
+                ")",
+                " ",
+            ),
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
-    print_readably: bool,
-    start: &str,
-    end: &str,
-    join: &str,
-) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+pub fn pr_seq<'a, I>(seq: I, print_readably: bool, start: &str, end: &str, join: &str) -> String
+where
+    I: IntoIterator<Item = &'a MalVal>,
+{
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
+
+impl MalVal {
+    // Prints self as EDN for edn-write. Unlike pr_str this fails for
+    // values EDN can't represent, such as functions, atoms and regexes.
+    pub fn edn_str(&self) -> Result<String, MalErr> {
+        Ok(match self {
+            Nil | Bool(_) | Int(_) | Str(_) | Sym(_) => self.pr_str(true),
+            Float(f) if f.is_finite() => self.pr_str(true),
+            List(..) | LazySeq(..) => edn_seq(&self.seq_vec()?, "(", ")")?,
+            Vector(v, _) => edn_seq(v, "[", "]")?,
+            Hash(hm, _) => edn_seq(hm.iter().flat_map(|(k, v)| vec![k, v]), "{", "}")?,
+            Set(hs, _) => edn_seq(hs, "#{", "}")?,
+            SortedMap(s, _) => edn_seq(s.entries.iter().flat_map(|(k, v)| vec![k, v]), "{", "}")?,
+            SortedSet(s, _) => edn_seq(s.entries.iter().map(|e| &e.0), "#{", "}")?,
+            _ => {
+                return Err(ErrString(format!(
+                    "edn-write: no EDN representation for {}",
+                    self.pr_str(true)
+                )))
+            }
+        })
+    }
+}
+
+fn edn_seq<'a, I>(seq: I, start: &str, end: &str) -> Result<String, MalErr>
+where
+    I: IntoIterator<Item = &'a MalVal>,
+{
+    let strs = seq
+        .into_iter()
+        .map(|x| x.edn_str())
+        .collect::<Result<Vec<String>, MalErr>>()?;
+    Ok(format!("{}{}{}", start, strs.join(" "), end))
+}
@@ -1,26 +1,29 @@
+use fnv::FnvHashMap;
+use itertools::Itertools;
 use regex::{Captures, Regex};
+use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{self, Bool, Float, Hash, Int, List, Nil, Set, Str, Sym, Vector};
+use crate::types::{error, hash_map, regex, MalArgs, MalErr, MalMap, MalRet, MalSet};
 
 #[derive(Debug, Clone)]
 struct Reader {
     tokens: Vec<String>,
     pos: usize,
+    // set when the input ran out inside a form or string
+    eof: bool,
 }
 
 impl Reader {
     fn next(&mut self) -> Result<String, MalErr> {
+        let token = self.peek()?;
         self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+        Ok(token)
     }
-    fn peek(&self) -> Result<String, MalErr> {
+    fn peek(&mut self) -> Result<String, MalErr> {
+        self.eof = self.pos >= self.tokens.len();
         Ok(self
             .tokens
             .get(self.pos)
@@ -32,7 +35,7 @@ impl Reader {
 fn tokenize(str: &str) -> Vec<String> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+            r###"[\s,]*(~@|#\{|[\[\]{}()'`~^@]|#?"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
@@ -60,6 +63,8 @@ fn unescape_str(s: &str) -> String {
 fn read_atom(rdr: &mut Reader) -> MalRet {
     lazy_static! {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+        static ref FLOAT_RE: Regex =
+            Regex::new(r"^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
     let token = rdr.next()?;
@@ -70,9 +75,21 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
         _ => {
             if INT_RE.is_match(&token) {
                 Ok(Int(token.parse().unwrap()))
+            } else if FLOAT_RE.is_match(&token) {
+                Ok(Float(token.parse().unwrap()))
+            } else if token.starts_with("#\"") {
+                if !STR_RE.is_match(&token[1..]) {
+                    rdr.eof = true;
+                    return error("expected '\"', got EOF");
+                }
+                // only \" is an escape in a regex literal; other backslashes
+                // are left for the regex itself
+                let pattern = token[2..token.len() - 1].replace("\\\"", "\"");
+                Ok(MalVal::Regex(regex(&pattern)?))
             } else if STR_RE.is_match(&token) {
                 Ok(Str(unescape_str(&token[1..token.len() - 1])))
             } else if token.starts_with("\"") {
+                rdr.eof = true;
                 error("expected '\"', got EOF")
             } else if token.starts_with(":") {
                 Ok(Str(format!("\u{29e}{}", &token[1..])))
@@ -85,7 +102,7 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let start = rdr.next()?;
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
@@ -100,6 +117,7 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     match end {
         ")" => Ok(list!(seq)),
         "]" => Ok(vector!(seq)),
+        "}" if start == "#{" => Ok(Set(seq.into_iter().collect(), Rc::new(Nil))),
         "}" => hash_map(seq),
         _ => error("read_seq unknown end value"),
     }
@@ -138,7 +156,7 @@ fn read_form(rdr: &mut Reader) -> MalRet {
         "]" => error("unexpected ']'"),
         "[" => read_seq(rdr, "]"),
         "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
+        "{" | "#{" => read_seq(rdr, "}"),
         _ => read_atom(rdr),
     }
 }
@@ -152,5 +170,344 @@ pub fn read_str(str: String) -> MalRet {
     read_form(&mut Reader {
         pos: 0,
         tokens: tokens,
+        eof: false,
     })
 }
+
+// Reads every form in str, as typed at the REPL, with the error that
+// stopped reading if there was one; the forms before it are still
+// returned. None when str ends inside a form or string, so the REPL can
+// read another line and try again.
+#[allow(dead_code)]
+pub fn read_forms(str: &str) -> Option<(MalArgs, Option<MalErr>)> {
+    let mut rdr = Reader {
+        pos: 0,
+        tokens: tokenize(str),
+        eof: false,
+    };
+    let mut forms = vec![];
+    while rdr.pos < rdr.tokens.len() {
+        match read_form(&mut rdr) {
+            Ok(form) => forms.push(form),
+            Err(_) if rdr.eof => return None,
+            Err(e) => return Some((forms, Some(e))),
+        }
+    }
+    Some((forms, None))
+}
+
+// EDN, the data subset of Clojure syntax, read for config and data files
+// rather than code: besides mal's collections it has #{} sets, #_ discard,
+// \c character literals (read as one-character strings) and #tag forms,
+// whose value is passed to the handler registered for the tag. There is
+// no quoting, deref or metadata syntax.
+
+thread_local! {
+    static TAG_HANDLERS: RefCell<FnvHashMap<String, MalVal>> = RefCell::new(FnvHashMap::default());
+}
+
+// Registers f as the handler for #tag, or removes the handler if f is
+// nil. Registered handlers take precedence over the built-in #inst and
+// #uuid, which check their string and return it.
+pub fn register_tag(tag: &str, f: MalVal) {
+    TAG_HANDLERS.with(|t| match f {
+        Nil => t.borrow_mut().remove(tag),
+        _ => t.borrow_mut().insert(tag.to_string(), f),
+    });
+}
+
+struct EdnReader<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> EdnReader<'a> {
+    fn fail<T>(&self, what: &str) -> Result<T, MalErr> {
+        Err(ErrString(format!(
+            "edn-read: {} at offset {}",
+            what, self.pos
+        )))
+    }
+
+    fn unexpected<T>(&self) -> Result<T, MalErr> {
+        match self.peek() {
+            Some(c) => self.fail(&format!("unexpected {:?}", c)),
+            None => self.fail("unexpected end of input"),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                self.pos += self.src[self.pos..]
+                    .find('\n')
+                    .unwrap_or(self.src.len() - self.pos);
+            } else if c.is_whitespace() || c == ',' {
+                self.pos += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // The run of symbol characters at the current position.
+    fn token(&mut self) -> &'a str {
+        let rest = &self.src[self.pos..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "()[]{}\",;".contains(c))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        &rest[..end]
+    }
+
+    // The next form, or None at a closing delimiter or the end of input.
+    fn form(&mut self) -> Result<Option<MalVal>, MalErr> {
+        self.skip_ws();
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let start = self.pos;
+        Ok(Some(match c {
+            ')' | ']' | '}' => return Ok(None),
+            '(' => list!(self.items(')')?),
+            '[' => vector!(self.items(']')?),
+            '{' => {
+                let items = self.items('}')?;
+                if !items.len().is_multiple_of(2) {
+                    self.pos = start;
+                    return self.fail("map literal must contain an even number of forms");
+                }
+                let mut hm = MalMap::default();
+                for (k, v) in items.into_iter().tuples() {
+                    if hm.insert(k.clone(), v).is_some() {
+                        self.pos = start;
+                        return self.fail(&format!("duplicate map key {}", k.pr_str(true)));
+                    }
+                }
+                Hash(hm, Rc::new(Nil))
+            }
+            '"' => Str(self.string()?),
+            '\\' => Str(self.character()?),
+            '#' => return self.dispatch(),
+            _ => self.atom()?,
+        }))
+    }
+
+    // Forms up to the closing delimiter, consuming it.
+    fn items(&mut self, close: char) -> Result<MalArgs, MalErr> {
+        self.pos += 1;
+        let mut items = vec![];
+        while let Some(mv) = self.form()? {
+            items.push(mv);
+        }
+        if self.peek() != Some(close) {
+            return self.unexpected();
+        }
+        self.pos += 1;
+        Ok(items)
+    }
+
+    fn dispatch(&mut self) -> Result<Option<MalVal>, MalErr> {
+        let start = self.pos;
+        self.pos += 1;
+        match self.peek() {
+            Some('{') => {
+                let mut hs = MalSet::default();
+                for mv in self.items('}')? {
+                    if hs.insert(mv.clone()).is_some() {
+                        self.pos = start;
+                        return self.fail(&format!("duplicate set element {}", mv.pr_str(true)));
+                    }
+                }
+                Ok(Some(Set(hs, Rc::new(Nil))))
+            }
+            Some('_') => {
+                self.pos += 1;
+                if self.form()?.is_none() {
+                    return self.unexpected();
+                }
+                self.form()
+            }
+            Some(c) if c.is_alphabetic() => {
+                let tag = self.token();
+                let val = match self.form()? {
+                    Some(val) => val,
+                    None => return self.unexpected(),
+                };
+                let handler = TAG_HANDLERS.with(|t| t.borrow().get(tag).cloned());
+                match handler {
+                    Some(f) => f.apply(vec![val]).map(Some),
+                    None => self.builtin_tag(start, tag, val).map(Some),
+                }
+            }
+            _ => self.unexpected(),
+        }
+    }
+
+    fn builtin_tag(&mut self, start: usize, tag: &str, val: MalVal) -> MalRet {
+        lazy_static! {
+            static ref INST_RE: Regex = Regex::new(
+                r"^\d{4}(-\d{2}(-\d{2}(T\d{2}:\d{2}(:\d{2}(\.\d+)?)?(Z|[-+]\d{2}:\d{2})?)?)?)?$"
+            )
+            .unwrap();
+            static ref UUID_RE: Regex =
+                Regex::new(r"^[[:xdigit:]]{8}(-[[:xdigit:]]{4}){3}-[[:xdigit:]]{12}$").unwrap();
+        }
+        let re: &Regex = match tag {
+            "inst" => &INST_RE,
+            "uuid" => &UUID_RE,
+            _ => {
+                self.pos = start;
+                return self.fail(&format!("no handler for tag #{}", tag));
+            }
+        };
+        match val {
+            Str(ref s) if re.is_match(s) => Ok(val.clone()),
+            _ => {
+                self.pos = start;
+                self.fail(&format!("invalid #{} {}", tag, val.pr_str(true)))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, MalErr> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.unexpected(),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = match self.peek() {
+                        Some(e) => e,
+                        None => return self.unexpected(),
+                    };
+                    self.pos += e.len_utf8();
+                    s.push(match e {
+                        '"' | '\\' => e,
+                        't' => '\t',
+                        'r' => '\r',
+                        'n' => '\n',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => self.hex_char()?,
+                        _ => {
+                            self.pos -= e.len_utf8() + 1;
+                            return self.fail(&format!("invalid escape \\{}", e));
+                        }
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex_char(&mut self) -> Result<char, MalErr> {
+        let c = match self.src.get(self.pos..self.pos + 4) {
+            Some(d) if d.chars().all(|c| c.is_ascii_hexdigit()) => {
+                ::std::char::from_u32(u32::from_str_radix(d, 16).unwrap())
+            }
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                self.pos += 4;
+                Ok(c)
+            }
+            None => self.fail("invalid \\u escape"),
+        }
+    }
+
+    fn character(&mut self) -> Result<String, MalErr> {
+        let start = self.pos;
+        self.pos += 1;
+        // the character itself may be a delimiter, as in \( or \,
+        let first = match self.peek() {
+            Some(c) => c,
+            None => return self.unexpected(),
+        };
+        self.pos += first.len_utf8();
+        let name = format!("{}{}", first, self.token());
+        let c = match &name[..] {
+            "newline" => '\n',
+            "return" => '\r',
+            "space" => ' ',
+            "tab" => '\t',
+            "formfeed" => '\u{c}',
+            "backspace" => '\u{8}',
+            _ if name.chars().count() == 1 => first,
+            _ if name.len() == 5 && first == 'u' => {
+                self.pos = start + 2;
+                self.hex_char()?
+            }
+            _ => {
+                self.pos = start;
+                return self.fail(&format!("invalid character \\{}", name));
+            }
+        };
+        Ok(c.to_string())
+    }
+
+    fn atom(&mut self) -> MalRet {
+        lazy_static! {
+            static ref INT_RE: Regex = Regex::new(r"^[-+]?[0-9]+N?$").unwrap();
+            static ref FLOAT_RE: Regex =
+                Regex::new(r"^[-+]?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?M?$").unwrap();
+        }
+        let start = self.pos;
+        let token = self.token();
+        if token.is_empty() {
+            return self.unexpected();
+        }
+        let num = token.trim_start_matches('+').trim_end_matches(['N', 'M']);
+        match token {
+            "nil" => Ok(Nil),
+            "true" => Ok(Bool(true)),
+            "false" => Ok(Bool(false)),
+            _ if INT_RE.is_match(token) => match num.parse() {
+                Ok(i) => Ok(Int(i)),
+                Err(_) => {
+                    self.pos = start;
+                    self.fail(&format!("integer {} out of range", token))
+                }
+            },
+            _ if FLOAT_RE.is_match(token) => Ok(Float(num.parse().unwrap())),
+            _ if token.starts_with(':') && token.len() > 1 => {
+                Ok(Str(format!("\u{29e}{}", &token[1..])))
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_digit() || c == ':') => {
+                self.pos = start;
+                self.fail(&format!("invalid token {}", token))
+            }
+            _ => Ok(Sym(token.to_string())),
+        }
+    }
+}
+
+// Reads the first form in s as EDN. Anything but whitespace and
+// comments after it is an error.
+pub fn read_edn(s: &str) -> MalRet {
+    let mut rdr = EdnReader { src: s, pos: 0 };
+    let mv = match rdr.form()? {
+        Some(mv) => mv,
+        None => return rdr.unexpected(),
+    };
+    let end = rdr.pos;
+    match rdr.form()? {
+        None if rdr.peek().is_none() => Ok(mv),
+        _ => {
+            rdr.pos = end;
+            rdr.skip_ws();
+            rdr.unexpected()
+        }
+    }
+}
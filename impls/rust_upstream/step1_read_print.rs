@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -13,9 +14,12 @@ use rustyline::Editor;
 mod types;
 use crate::types::format_error;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
+mod condition;
+#[allow(dead_code)]
 mod env;
 
 fn main() {
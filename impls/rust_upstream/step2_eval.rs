@@ -5,6 +5,7 @@ use fnv::FnvHashMap;
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -17,11 +18,14 @@ use rustyline::Editor;
 mod types;
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalVal};
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
+mod condition;
+#[allow(dead_code)]
 mod env;
 
 pub type Env = FnvHashMap<String, MalVal>;
@@ -53,11 +57,11 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -72,7 +76,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
                     let ref f = el[0].clone();
-                    f.apply(el[1..].to_vec())
+                    f.apply(el.iter().skip(1).cloned().collect())
                 }
                 _ => error("expected a list"),
             }
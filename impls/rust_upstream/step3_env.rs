@@ -1,11 +1,11 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -17,9 +17,12 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
+mod condition;
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
@@ -47,11 +50,11 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -71,9 +74,9 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Sym(ref a0sym) if a0sym == "let*" => {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
-                    match a1 {
-                        List(ref binds, _) | Vector(ref binds, _) => {
-                            for (b, e) in binds.iter().tuples() {
+                    match a1.seq_iter() {
+                        Some(binds) => {
+                            for (b, e) in binds.tuples() {
                                 match b {
                                     Sym(_) => {
                                         let _ = env_set(
@@ -88,7 +91,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                                 }
                             }
                         }
-                        _ => {
+                        None => {
                             return error("let* with non-List bindings");
                         }
                     };
@@ -97,7 +100,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
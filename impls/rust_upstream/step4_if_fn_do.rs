@@ -1,13 +1,14 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
+extern crate unicode_segmentation;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -16,7 +17,9 @@ use rustyline::Editor;
 #[macro_use]
 mod types;
 use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
+mod condition;
 mod env;
 mod printer;
 mod reader;
@@ -48,11 +51,11 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -72,9 +75,9 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Sym(ref a0sym) if a0sym == "let*" => {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
-                    match a1 {
-                        List(ref binds, _) | Vector(ref binds, _) => {
-                            for (b, e) in binds.iter().tuples() {
+                    match a1.seq_iter() {
+                        Some(binds) => {
+                            for (b, e) in binds.tuples() {
                                 match b {
                                     Sym(_) => {
                                         let _ = env_set(
@@ -89,13 +92,13 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                                 }
                             }
                         }
-                        _ => {
+                        None => {
                             return error("let* with non-List bindings");
                         }
                     };
                     eval(a2, let_env)
                 }
-                Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l[1..].to_vec()), &env)? {
+                Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l.rest()), &env)? {
                     List(el, _) => Ok(el.last().unwrap_or(&Nil).clone()),
                     _ => error("invalid do form"),
                 },
@@ -122,7 +125,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
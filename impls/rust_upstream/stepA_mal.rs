@@ -1,15 +1,17 @@
 #![allow(non_snake_case)]
 
+use std::panic;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
+extern crate unicode_segmentation;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -17,13 +19,20 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ErrMalVal, ErrRecur, ErrRestart, ErrString};
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    catch_panic, error, fn_arity, fn_clause, format_error, lazy_seq, MalArgs, MalErr, MalMap,
+    MalRet, MalSet, MalVal,
+};
+mod completer;
+use crate::completer::MalHelper;
+mod condition;
+use crate::condition::{restart_case, with_catch, with_handler, Outcome};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_bind, env_destructure, env_find, env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
 mod core;
 
@@ -62,10 +71,15 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            return qq_iter(&v.iter().cloned().collect());
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => {
+            return list![
+                Sym("vec".to_string()),
+                qq_iter(&v.iter().cloned().collect())
+            ]
+        }
+        Hash(_, _) | Set(_, _) | Sym(_) => return list![Sym("quote".to_string()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -75,7 +89,9 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
         List(v, _) => match v[0] {
             Sym(ref s) => match env_find(env, s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => {
+                        Some((f, v.iter().skip(1).cloned().collect()))
+                    }
                     _ => None,
                 },
                 _ => None,
@@ -100,6 +116,55 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
+// The params and body of (fn* params body). A fn* made of clauses like
+// (fn* ([a] ..) ([a b] ..) ([a b & more] ..)) dispatches on the number of
+// arguments: its params are nil and its body the list of clauses, see
+// types::fn_clause.
+fn fn_parts(forms: &[MalVal]) -> Result<(MalVal, MalVal), MalErr> {
+    let is_clause = |f: &MalVal| match f {
+        List(c, _) => matches!(c.first(), Some(Vector(..))),
+        _ => false,
+    };
+    if forms.is_empty() || !forms.iter().all(is_clause) {
+        return match forms.first() {
+            Some(p) if p.seq_iter().is_some() => {
+                Ok((p.clone(), forms.get(1).unwrap_or(&Nil).clone()))
+            }
+            p => Err(ErrString(format!(
+                "fn*: parameters must be a list or vector, got {}",
+                p.unwrap_or(&Nil).pr_str(true)
+            ))),
+        };
+    }
+    let mut fixed = vec![];
+    let mut variadic = None;
+    for f in forms.iter() {
+        let (min, more) = fn_arity(f.seq_iter().unwrap().next().unwrap());
+        if more && variadic.is_some() {
+            return Err(ErrString(
+                "fn*: only one clause can be variadic".to_string(),
+            ));
+        } else if more {
+            variadic = Some(min);
+        } else if fixed.contains(&min) {
+            return Err(ErrString(format!(
+                "fn*: more than one clause takes {} args",
+                min
+            )));
+        } else {
+            fixed.push(min);
+        }
+    }
+    if let (Some(&n), Some(min)) = (fixed.iter().max(), variadic) {
+        if n > min {
+            return Err(ErrString(
+                "fn*: a fixed arity clause cannot take more args than the variadic one".to_string(),
+            ));
+        }
+    }
+    Ok((Nil, list!(forms.to_vec())))
+}
+
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(_) => Ok(env_get(&env, &ast)?),
@@ -118,17 +183,62 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for mv in hs.iter() {
+                new_hs.insert(eval(mv.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+// The fewest forms each special form takes after its name, so that a
+// short form is reported instead of indexing past its end.
+fn check_special_form(name: &str, n: usize) -> Result<(), MalErr> {
+    let min = match name {
+        "def!" | "let*" | "loop*" | "defmacro!" | "handler-bind" | "if" => 2,
+        "quote" | "quasiquote" | "quasiquoteexpand" | "macroexpand" | "try*" | "restart-case"
+        | "do" | "fn*" | "eval" => 1,
+        _ => return Ok(()),
+    };
+    if n < min {
+        return Err(ErrString(format!(
+            "{}: wrong number of args ({}), expected at least {}",
+            name, n, min
+        )));
+    }
+    Ok(())
+}
+
+// What a recur in tail position jumps back to: the innermost loop*, the
+// function whose body eval continued into, or the call of a function
+// body run by MalVal::apply, which repeats the call on ErrRecur.
+enum Recur {
+    Loop(MalArgs, MalVal, Env),
+    Fn(Rc<MalVal>, Rc<MalVal>, Env),
+    Apply,
+}
+
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    eval_tail(ast, env, None)
+}
+
+fn eval_fn_body(ast: MalVal, env: Env) -> MalRet {
+    eval_tail(ast, env, Some(Recur::Apply))
+}
+
+// Only the forms eval continues into through the 'tco loop are in tail
+// position. Everything else is evaluated by a nested eval, where there is
+// nothing for a recur to jump back to.
+fn eval_tail(mut ast: MalVal, mut env: Env, mut recur: Option<Recur>) -> MalRet {
     let ret: MalRet;
 
     'tco: loop {
@@ -150,6 +260,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     return Ok(ast);
                 }
                 let a0 = &l[0];
+                if let Sym(ref a0sym) = a0 {
+                    check_special_form(a0sym, l.len() - 1)?;
+                }
                 match a0 {
                     Sym(ref a0sym) if a0sym == "def!" => {
                         env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
@@ -157,30 +270,69 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
                         let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
+                        match a1.seq_iter() {
+                            Some(binds) => {
+                                for (b, e) in binds.tuples() {
+                                    env_destructure(&env, b, eval(e.clone(), env.clone())?, eval)?;
                                 }
                             }
-                            _ => {
+                            None => {
                                 return error("let* with non-List bindings");
                             }
                         };
                         ast = a2;
                         continue 'tco;
                     }
+                    Sym(ref a0sym) if a0sym == "loop*" => {
+                        let binds = match l[1].seq_iter() {
+                            Some(binds) => binds.cloned().collect::<MalArgs>(),
+                            None => return error("loop* with non-List bindings"),
+                        };
+                        if !binds.len().is_multiple_of(2) {
+                            return error("loop* with odd number of binding forms");
+                        }
+                        let outer = env.clone();
+                        env = env_new(Some(outer.clone()));
+                        for (b, e) in binds.iter().tuples() {
+                            env_destructure(&env, b, eval(e.clone(), env.clone())?, eval)?;
+                        }
+                        let forms = binds.iter().step_by(2).cloned().collect();
+                        recur = Some(Recur::Loop(forms, l[2].clone(), outer));
+                        ast = l[2].clone();
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "recur" => {
+                        let args = l
+                            .iter()
+                            .skip(1)
+                            .map(|a| eval(a.clone(), env.clone()))
+                            .collect::<Result<MalArgs, MalErr>>()?;
+                        match recur {
+                            Some(Recur::Loop(ref forms, ref body, ref outer)) => {
+                                if args.len() != forms.len() {
+                                    return Err(ErrString(format!(
+                                        "recur: wrong number of args ({}), expected {}",
+                                        args.len(),
+                                        forms.len()
+                                    )));
+                                }
+                                env = env_new(Some(outer.clone()));
+                                for (b, a) in forms.iter().zip(args) {
+                                    env_destructure(&env, b, a, eval)?;
+                                }
+                                ast = body.clone();
+                                continue 'tco;
+                            }
+                            Some(Recur::Fn(ref params, ref body, ref fenv)) => {
+                                let (p, a) = fn_clause(params, body, args.len())?;
+                                env = env_bind(Some(fenv.clone()), p, args, eval)?;
+                                ast = a;
+                                continue 'tco;
+                            }
+                            Some(Recur::Apply) => Err(ErrRecur(args)),
+                            None => error("recur must be in tail position of a loop* or fn*"),
+                        }
+                    }
                     Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
                     Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
                     Sym(ref a0sym) if a0sym == "quasiquote" => {
@@ -218,28 +370,78 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             (_, e) => return e,
                         }
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
+                    Sym(ref a0sym) if a0sym == "try*" => {
+                        // with a catch*, errors in the body belong to it and
+                        // not to handlers outside
+                        let res = if l.len() >= 3 {
+                            with_catch(|| catch_panic(|| eval(l[1].clone(), env.clone())))
+                        } else {
+                            eval(l[1].clone(), env.clone())
+                        };
+                        match res {
+                            // restarts unwind to their restart-case, not to catch*
+                            Err(e @ ErrRestart(..)) => Err(e),
+                            Err(ref e) if l.len() >= 3 => {
+                                let exc = match e {
+                                    ErrMalVal(mv) => mv.clone(),
+                                    ErrString(s) => Str(s.to_string()),
+                                    ErrRestart(..) | ErrRecur(..) => unreachable!(),
+                                };
+                                match l[2].clone() {
+                                    List(c, _) if c.len() == 3 => {
+                                        let catch_env = env_bind(
+                                            Some(env.clone()),
+                                            list!(vec![c[1].clone()]),
+                                            vec![exc],
+                                            eval,
+                                        )?;
+                                        eval(c[2].clone(), catch_env)
+                                    }
+                                    _ => error("invalid catch block"),
                                 }
-                                _ => error("invalid catch block"),
                             }
+                            res => res,
                         }
-                        res => res,
-                    },
+                    }
+                    Sym(ref a0sym) if a0sym == "handler-bind" => {
+                        let handler = eval(l[1].clone(), env.clone())?;
+                        with_handler(handler, || eval(l[2].clone(), env.clone()))
+                    }
+                    Sym(ref a0sym) if a0sym == "restart-case" => {
+                        let mut names = vec![];
+                        let mut clauses = vec![];
+                        for c in l.iter().skip(2) {
+                            match c {
+                                List(c, _) if c.len() == 3 => match c[0] {
+                                    Sym(ref s) => {
+                                        names.push(s.to_string());
+                                        clauses.push((c[1].clone(), c[2].clone()));
+                                    }
+                                    _ => return error("restart name must be a symbol"),
+                                },
+                                _ => return error("invalid restart-case clause"),
+                            }
+                        }
+                        match restart_case(&names, || eval(l[1].clone(), env.clone()))? {
+                            Outcome::Done(v) => Ok(v),
+                            Outcome::Restart(i, args) => {
+                                let (ref params, ref body) = clauses[i];
+                                env = env_bind(Some(env.clone()), params.clone(), args, eval)?;
+                                ast = body.clone();
+                                continue 'tco;
+                            }
+                        }
+                    }
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(
+                            &list!(l
+                                .iter()
+                                .skip(1)
+                                .take(l.len() - 2)
+                                .cloned()
+                                .collect::<MalArgs>()),
+                            &env,
+                        )? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -263,9 +465,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                     }
                     Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        let (a1, a2) = fn_parts(&l.iter().skip(1).cloned().collect::<MalArgs>())?;
                         Ok(MalFunc {
-                            eval: eval,
+                            eval: eval_fn_body,
                             ast: Rc::new(a2),
                             env: env,
                             params: Rc::new(a1),
@@ -273,32 +475,38 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             meta: Rc::new(Nil),
                         })
                     }
+                    Sym(ref a0sym) if a0sym == "lazy-seq" => {
+                        let body = list!(l.rest().cons(Sym("do".to_string())));
+                        let env = env.clone();
+                        Ok(lazy_seq(move || eval(body.clone(), env.clone())))
+                    }
                     Sym(ref a0sym) if a0sym == "eval" => {
                         ast = eval(l[1].clone(), env.clone())?;
                         while let Some(ref e) = env.clone().outer {
                             env = e.clone();
                         }
+                        recur = None;
                         continue 'tco;
                     }
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args: MalArgs = el.iter().skip(1).cloned().collect();
                             match f {
-                                Func(_, _) => f.apply(args),
                                 MalFunc {
                                     ast: mast,
                                     env: menv,
                                     params,
                                     ..
                                 } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
+                                    let (p, a) = fn_clause(params, mast, args.len())?;
+                                    env = env_bind(Some(menv.clone()), p, args, eval)?;
+                                    recur =
+                                        Some(Recur::Fn(params.clone(), mast.clone(), menv.clone()));
+                                    ast = a;
                                     continue 'tco;
                                 }
-                                _ => error("attempt to call non-function"),
+                                _ => f.apply(args),
                             }
                         }
                         _ => error("expected a list"),
@@ -320,8 +528,16 @@ fn print(ast: &MalVal) -> String {
 }
 
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
-    let ast = read(str)?;
-    let exp = eval(ast, env.clone())?;
+    eval_print(read(str)?, env)
+}
+
+fn eval_print(ast: MalVal, env: &Env) -> Result<String, MalErr> {
+    let exp = catch_panic(|| {
+        let exp = eval(ast, env.clone())?;
+        // realized here so errors from lazy seqs are reported, not printed
+        exp.realize()?;
+        Ok(exp)
+    })?;
     Ok(print(&exp))
 }
 
@@ -329,8 +545,10 @@ fn main() {
     let mut args = std::env::args();
     let arg1 = args.nth(1);
 
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<()>::new();
+    // panics are reported through rep as errors
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut rl = Editor::<MalHelper>::new();
     if rl.load_history(".mal-history").is_err() {
         eprintln!("No previous history.");
     }
@@ -340,7 +558,12 @@ fn main() {
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(
+        &repl_env,
+        "*ARGV*",
+        list!(args.map(Str).collect::<MalArgs>()),
+    );
+    rl.set_helper(Some(MalHelper::new(repl_env.clone())));
 
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
@@ -350,6 +573,7 @@ fn main() {
         &repl_env,
     );
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
+    let _ = rep("(defmacro! with-open (fn* (bs & body) (if (empty? bs) `(do ~@body) `(let* [~(nth bs 0) ~(nth bs 1)] (try* (let* [res (with-open ~(vec (rest (rest bs))) ~@body)] (do (close ~(nth bs 0)) res)) (catch* e (do (close ~(nth bs 0)) (throw e))))))))", &repl_env);
 
     // Invoked with arguments
     if let Some(f) = arg1 {
@@ -362,22 +586,36 @@ fn main() {
         }
     }
 
-    // main repl loop
+    // main repl loop: lines are collected, with a continuation prompt,
+    // until they hold only complete forms, then each form is evaluated
     let _ = rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    let mut input = String::new();
     loop {
-        let readline = rl.readline("user> ");
+        let readline = rl.readline(if input.is_empty() { "user> " } else { "  ... " });
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
+                input.push_str(&line);
+                input.push('\n');
+                let (forms, read_err) = match reader::read_forms(&input) {
+                    Some(read) => read,
+                    None => continue,
+                };
+                rl.add_history_entry(input.trim_end());
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
-                    match rep(&line, &repl_env) {
+                input.clear();
+                for ast in forms {
+                    match eval_print(ast, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
                     }
                 }
+                // the forms before a syntax error still run
+                if let Some(e) = read_err {
+                    println!("Error: {}", format_error(e));
+                }
             }
-            Err(ReadlineError::Interrupted) => continue,
+            // Ctrl-C abandons a partly typed form
+            Err(ReadlineError::Interrupted) => input.clear(),
             Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Error: {:?}", err);
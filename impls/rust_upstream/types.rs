@@ -1,24 +1,42 @@
+use std::any::Any;
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash as StdHash, Hasher};
+use std::io::{BufReader, Lines};
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::Index;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHasher};
+use im_rc::vector::Iter as VecIter;
+use im_rc::{HashMap, HashSet, OrdMap, Vector as ImVector};
 use itertools::Itertools;
 
-use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::env::{env_bind, Env, EnvStruct};
+use crate::types::MalErr::{ErrMalVal, ErrRecur, ErrRestart, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Float, Func, Hash, Int, LazySeq, List, MalFunc, Nil, Reader, Regex, Set, SortedMap,
+    SortedSet, Str, Sym, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    Float(f64),
     Str(String),
     Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
+    List(MalList, Rc<MalVal>),
+    Vector(MalVec, Rc<MalVal>),
+    Hash(MalMap, Rc<MalVal>),
+    Set(MalSet, Rc<MalVal>),
+    SortedMap(Sorted, Rc<MalVal>),
+    SortedSet(Sorted, Rc<MalVal>),
     Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
@@ -29,36 +47,419 @@ pub enum MalVal {
         meta: Rc<MalVal>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    LazySeq(Rc<RefCell<Lazy>>, Rc<MalVal>),
+    Regex(Rc<::regex::Regex>),
+    Reader(Rc<RefCell<LineReader>>),
 }
 
+// The variants keep the Err prefix of the original two so that they read
+// the same wherever they are imported unqualified.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    ErrRestart(usize, MalArgs),
+    // A recur in tail position of a function body run by apply, with the
+    // args to call the function with again.
+    ErrRecur(MalArgs),
 }
 
 pub type MalArgs = Vec<MalVal>;
+
+// Persistent collections: updates share structure with the original, so
+// cons, conj, rest, assoc and dissoc don't copy the whole collection.
+pub type MalVec = ImVector<MalVal>;
+pub type MalSet = HashSet<MalVal, FnvBuildHasher>;
+
+// Hash-maps iterate in insertion order, so printing a map and keys, vals
+// and seq give the same result on every run. Each key stores its value
+// and a sequence number that indexes the key in `order`; assoc on an
+// existing key keeps its position, dissoc forgets it.
+#[derive(Clone, Default)]
+pub struct MalMap {
+    entries: HashMap<MalVal, (u64, MalVal), FnvBuildHasher>,
+    order: OrdMap<u64, MalVal>,
+    next: u64,
+}
+
+impl MalMap {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, k: &MalVal) -> Option<&MalVal> {
+        self.entries.get(k).map(|e| &e.1)
+    }
+
+    pub fn contains_key(&self, k: &MalVal) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    pub fn insert(&mut self, k: MalVal, v: MalVal) -> Option<MalVal> {
+        let n = match self.entries.get(&k) {
+            Some(&(n, _)) => n,
+            None => {
+                self.next += 1;
+                self.order.insert(self.next, k.clone());
+                self.next
+            }
+        };
+        self.entries.insert(k, (n, v)).map(|e| e.1)
+    }
+
+    pub fn update(&self, k: MalVal, v: MalVal) -> MalMap {
+        let mut hm = self.clone();
+        hm.insert(k, v);
+        hm
+    }
+
+    pub fn remove(&mut self, k: &MalVal) {
+        if let Some((n, _)) = self.entries.remove(k) {
+            self.order.remove(&n);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MalVal, &MalVal)> {
+        self.order.values().map(move |k| (k, &self.entries[k].1))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &MalVal> {
+        self.order.values()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|e| e.1)
+    }
+}
+
+// Maps with the same entries are equal whatever order they were built in.
+impl PartialEq for MalMap {
+    fn eq(&self, other: &MalMap) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl fmt::Debug for MalMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<(MalVal, MalVal)> for MalMap {
+    fn from_iter<I: IntoIterator<Item = (MalVal, MalVal)>>(iter: I) -> MalMap {
+        let mut hm = MalMap::default();
+        for (k, v) in iter {
+            hm.insert(k, v);
+        }
+        hm
+    }
+}
+
+// Sorted maps and sets keep their entries ordered by cmp: nil for the
+// natural order of compare, otherwise a comparator function as taken by
+// sort. Entries are kept in a persistent vector and located by binary
+// search (see sorted_find); the values of a sorted set are nil.
+#[derive(Debug, Clone)]
+pub struct Sorted {
+    pub cmp: Rc<MalVal>,
+    pub entries: ImVector<(MalVal, MalVal)>,
+}
 pub type MalRet = Result<MalVal, MalErr>;
 
+// A text file opened by open-reader and read a line at a time. close
+// drops the file handle; reading after that is an error.
+pub struct LineReader {
+    pub path: String,
+    pub lines: Option<Lines<BufReader<File>>>,
+}
+
+impl fmt::Debug for LineReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LineReader({:?})", self.path)
+    }
+}
+
+// Lists are persistent singly linked lists, so cons, first and rest are
+// O(1) and share the tail. A list made from a vector (by seq, cons or
+// rest) keeps the vector as its tail instead of copying it.
+#[derive(Debug, Clone)]
+pub struct MalList {
+    head: Link,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Link {
+    Cons(Rc<Node>),
+    Vec(MalVec),
+}
+
+#[derive(Debug)]
+struct Node {
+    val: MalVal,
+    next: Link,
+}
+
+impl MalList {
+    pub fn new() -> MalList {
+        MalList {
+            head: Link::Vec(MalVec::new()),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn cons(&self, val: MalVal) -> MalList {
+        MalList {
+            head: Link::Cons(Rc::new(Node {
+                val,
+                next: self.head.clone(),
+            })),
+            len: self.len + 1,
+        }
+    }
+
+    pub fn first(&self) -> Option<&MalVal> {
+        match self.head {
+            Link::Cons(ref n) => Some(&n.val),
+            Link::Vec(ref v) => v.front(),
+        }
+    }
+
+    pub fn rest(&self) -> MalList {
+        match self.head {
+            _ if self.len == 0 => MalList::new(),
+            Link::Cons(ref n) => MalList {
+                head: n.next.clone(),
+                len: self.len - 1,
+            },
+            Link::Vec(ref v) => MalList {
+                head: Link::Vec(v.skip(1)),
+                len: self.len - 1,
+            },
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&MalVal> {
+        self.iter().nth(idx)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        self.iter().last()
+    }
+
+    pub fn iter(&self) -> SeqIter<'_> {
+        SeqIter::new(&self.head)
+    }
+}
+
+// Unlink the cells one at a time; the default recursive drop would
+// overflow the stack on a long list.
+impl Drop for MalList {
+    fn drop(&mut self) {
+        let mut link = mem::replace(&mut self.head, Link::Vec(MalVec::new()));
+        while let Link::Cons(node) = link {
+            match Rc::try_unwrap(node) {
+                Ok(mut node) => link = mem::replace(&mut node.next, Link::Vec(MalVec::new())),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl From<Vec<MalVal>> for MalList {
+    fn from(v: Vec<MalVal>) -> MalList {
+        v.into_iter().rev().fold(MalList::new(), |l, x| l.cons(x))
+    }
+}
+
+impl From<MalVec> for MalList {
+    fn from(v: MalVec) -> MalList {
+        MalList {
+            len: v.len(),
+            head: Link::Vec(v),
+        }
+    }
+}
+
+impl FromIterator<MalVal> for MalList {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalList {
+        MalList::from(iter.into_iter().collect::<MalArgs>())
+    }
+}
+
+impl Index<usize> for MalList {
+    type Output = MalVal;
+
+    fn index(&self, idx: usize) -> &MalVal {
+        self.get(idx).expect("list index out of bounds")
+    }
+}
+
+impl PartialEq for MalList {
+    fn eq(&self, other: &MalList) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a MalList {
+    type Item = &'a MalVal;
+    type IntoIter = SeqIter<'a>;
+
+    fn into_iter(self) -> SeqIter<'a> {
+        self.iter()
+    }
+}
+
+// A lazy seq starts out as a thunk, run at most once, the first time the
+// seq is looked into. Its result replaces the thunk: either the seq is
+// empty, or it has a first element and a rest which may be lazy itself.
+pub enum Lazy {
+    Thunk(Rc<dyn Fn() -> MalRet>),
+    Empty,
+    Cons(MalVal, MalVal),
+}
+
+impl fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lazy::Thunk(_) => write!(f, "Thunk"),
+            Lazy::Empty => write!(f, "Empty"),
+            Lazy::Cons(a, d) => f.debug_tuple("Cons").field(a).field(d).finish(),
+        }
+    }
+}
+
+// As for MalList, unlink a realized chain iteratively.
+impl Drop for Lazy {
+    fn drop(&mut self) {
+        let mut next = match self {
+            Lazy::Cons(_, d) => mem::replace(d, Nil),
+            _ => return,
+        };
+        while let LazySeq(cell, _) = next {
+            next = match Rc::try_unwrap(cell) {
+                Ok(cell) => match cell.into_inner() {
+                    Lazy::Cons(_, ref mut d) => mem::replace(d, Nil),
+                    _ => break,
+                },
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+pub fn lazy_seq<F: Fn() -> MalRet + 'static>(f: F) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(Lazy::Thunk(Rc::new(f)))), Rc::new(Nil))
+}
+
+// A realized lazy seq; rest is any seq and is not looked into.
+pub fn lazy_cons(first: MalVal, rest: MalVal) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(Lazy::Cons(first, rest))), Rc::new(Nil))
+}
+
+// Run the thunk of a lazy seq and cache its result. A thunk returning
+// another unrealized lazy seq is followed in a loop rather than by
+// recursion, and every cell passed through caches the same result.
+fn force(cell: &Rc<RefCell<Lazy>>) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    let mut pending = vec![cell.clone()];
+    let res = loop {
+        let thunk = match *pending[pending.len() - 1].borrow() {
+            Lazy::Thunk(ref f) => f.clone(),
+            Lazy::Empty => break None,
+            Lazy::Cons(ref a, ref d) => break Some((a.clone(), d.clone())),
+        };
+        match thunk()? {
+            LazySeq(next, _) => pending.push(next),
+            mv => break mv.uncons()?,
+        }
+    };
+    for cell in pending {
+        *cell.borrow_mut() = match res {
+            Some((ref a, ref d)) => Lazy::Cons(a.clone(), d.clone()),
+            None => Lazy::Empty,
+        };
+    }
+    Ok(res)
+}
+
+// Compare two seqs element by element, realizing only as much of them as
+// needed to tell them apart.
+fn seq_eq(mut a: MalVal, mut b: MalVal) -> bool {
+    loop {
+        match (a.uncons(), b.uncons()) {
+            (Ok(None), Ok(None)) => return true,
+            (Ok(Some((x, xs))), Ok(Some((y, ys)))) if x == y => {
+                a = xs;
+                b = ys;
+            }
+            _ => return false,
+        }
+    }
+}
+
+// Iterates the elements of a list or vector.
+pub struct SeqIter<'a>(IterState<'a>);
+
+enum IterState<'a> {
+    Cons(&'a Node),
+    Vec(VecIter<'a, MalVal>),
+}
+
+impl<'a> SeqIter<'a> {
+    fn new(link: &'a Link) -> SeqIter<'a> {
+        match link {
+            Link::Cons(n) => SeqIter(IterState::Cons(n)),
+            Link::Vec(v) => SeqIter(IterState::Vec(v.iter())),
+        }
+    }
+}
+
+impl<'a> Iterator for SeqIter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self.0 {
+            IterState::Cons(n) => {
+                *self = SeqIter::new(&n.next);
+                Some(&n.val)
+            }
+            IterState::Vec(ref mut it) => it.next(),
+        }
+    }
+}
+
 // type utility macros
 
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    List($crate::types::MalList::from($seq),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List($crate::types::MalList::from(v),Rc::new(Nil))
   }}
 }
 
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    Vector($crate::types::MalVec::from($seq),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector($crate::types::MalVec::from(v),Rc::new(Nil))
   }}
 }
 
@@ -72,9 +473,51 @@ pub fn format_error(e: MalErr) -> String {
     match e {
         ErrString(s) => s.clone(),
         ErrMalVal(mv) => mv.pr_str(true),
+        ErrRestart(..) => "restart invoked outside of its restart-case".to_string(),
+        ErrRecur(..) => "recur must be in tail position of a loop* or fn*".to_string(),
     }
 }
 
+// A panic in a builtin becomes an ordinary error, which try* can catch,
+// instead of ending the session and losing every definition made so far.
+pub fn catch_panic<F>(f: F) -> MalRet
+where
+    F: FnOnce() -> MalRet,
+{
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|p: Box<dyn Any + Send>| {
+        let msg = match (p.downcast_ref::<&str>(), p.downcast_ref::<String>()) {
+            (Some(s), _) => s.to_string(),
+            (_, Some(s)) => s.to_string(),
+            _ => "unknown panic".to_string(),
+        };
+        Err(ErrString(format!("internal error: {}", msg)))
+    })
+}
+
+// Compile a pattern, reusing the result when the same pattern was compiled
+// before, so making a regex from a string inside a loop is cheap.
+pub fn regex(pattern: &str) -> Result<Rc<::regex::Regex>, MalErr> {
+    thread_local! {
+        static CACHE: RefCell<FnvHashMap<String, Rc<::regex::Regex>>> =
+            RefCell::new(FnvHashMap::default());
+    }
+    if let Some(re) = CACHE.with(|c| c.borrow().get(pattern).cloned()) {
+        return Ok(re);
+    }
+    let re = match ::regex::Regex::new(pattern) {
+        Ok(re) => Rc::new(re),
+        Err(e) => return Err(ErrString(format!("invalid regex: {}", e))),
+    };
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.len() >= 1024 {
+            c.clear();
+        }
+        c.insert(pattern.to_string(), re.clone());
+    });
+    Ok(re)
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
@@ -90,7 +533,12 @@ impl MalVal {
 
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            List(l, _) => Ok(Bool(l.is_empty())),
+            Vector(v, _) => Ok(Bool(v.is_empty())),
+            LazySeq(..) => Ok(Bool(self.uncons()?.is_none())),
+            Hash(hm, _) => Ok(Bool(hm.is_empty())),
+            Set(hs, _) => Ok(Bool(hs.is_empty())),
+            SortedMap(s, _) | SortedSet(s, _) => Ok(Bool(s.entries.is_empty())),
             Nil => Ok(Bool(true)),
             _ => error("invalid type for empty?"),
         }
@@ -98,15 +546,130 @@ impl MalVal {
 
     pub fn count(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            List(l, _) => Ok(Int(l.len() as i64)),
+            Vector(v, _) => Ok(Int(v.len() as i64)),
+            LazySeq(..) => Ok(Int(self.seq_vec()?.len() as i64)),
+            Hash(hm, _) => Ok(Int(hm.len() as i64)),
+            Set(hs, _) => Ok(Int(hs.len() as i64)),
+            SortedMap(s, _) | SortedSet(s, _) => Ok(Int(s.entries.len() as i64)),
             Nil => Ok(Int(0)),
             _ => error("invalid type for count"),
         }
     }
 
+    // The elements of a list or vector, None for any other value.
+    pub fn seq_iter(&self) -> Option<SeqIter<'_>> {
+        match self {
+            List(l, _) => Some(l.iter()),
+            Vector(v, _) => Some(SeqIter(IterState::Vec(v.iter()))),
+            _ => None,
+        }
+    }
+
+    // The entries of a hash-map or sorted map, None for any other value.
+    pub fn map_entries(&self) -> Option<MalMap> {
+        match self {
+            Hash(hm, _) => Some(hm.clone()),
+            SortedMap(s, _) => Some(s.entries.iter().cloned().collect()),
+            _ => None,
+        }
+    }
+
+    // The elements of a hash-set or sorted set, None for any other value.
+    pub fn set_elems(&self) -> Option<MalSet> {
+        match self {
+            Set(hs, _) => Some(hs.clone()),
+            SortedSet(s, _) => Some(s.entries.iter().map(|e| e.0.clone()).collect()),
+            _ => None,
+        }
+    }
+
+    pub fn sequential_q(&self) -> bool {
+        matches!(self, List(..) | Vector(..) | LazySeq(..))
+    }
+
+    // Split a seq into its first element and the rest, realizing it if it
+    // is lazy. None when the seq is empty.
+    pub fn uncons(&self) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+        match self {
+            Nil => Ok(None),
+            List(l, _) => Ok(l.first().map(|mv| (mv.clone(), list!(l.rest())))),
+            Vector(v, _) => Ok(v
+                .front()
+                .map(|mv| (mv.clone(), list!(MalList::from(v.skip(1)))))),
+            LazySeq(cell, _) => force(cell),
+            Set(hs, _) => list!(hs.iter().cloned().collect::<MalArgs>()).uncons(),
+            Hash(hm, _) => list!(hm
+                .iter()
+                .map(|(k, v)| vector![vec![k.clone(), v.clone()]])
+                .collect::<MalArgs>())
+            .uncons(),
+            SortedMap(s, _) => list!(s
+                .entries
+                .iter()
+                .map(|(k, v)| vector![vec![k.clone(), v.clone()]])
+                .collect::<MalArgs>())
+            .uncons(),
+            SortedSet(s, _) => {
+                list!(s.entries.iter().map(|e| e.0.clone()).collect::<MalArgs>()).uncons()
+            }
+            _ => Err(ErrString(format!("not a seq: {}", self.pr_str(true)))),
+        }
+    }
+
+    // The elements of a finite seq, realizing all of it if it is lazy.
+    pub fn seq_vec(&self) -> Result<MalArgs, MalErr> {
+        let mut res = vec![];
+        let mut seq = self.clone();
+        loop {
+            if let Some(it) = seq.seq_iter() {
+                res.extend(it.cloned());
+                return Ok(res);
+            }
+            match seq.uncons()? {
+                Some((mv, rest)) => {
+                    res.push(mv);
+                    seq = rest;
+                }
+                None => return Ok(res),
+            }
+        }
+    }
+
+    // Realize every lazy seq reachable from self, so errors from their
+    // thunks surface before it is printed.
+    pub fn realize(&self) -> Result<(), MalErr> {
+        match self {
+            List(..) | Vector(..) | LazySeq(..) => {
+                for mv in self.seq_vec()?.iter() {
+                    mv.realize()?;
+                }
+            }
+            Hash(hm, _) => {
+                for (k, v) in hm.iter() {
+                    k.realize()?;
+                    v.realize()?;
+                }
+            }
+            Set(hs, _) => {
+                for mv in hs.iter() {
+                    mv.realize()?;
+                }
+            }
+            SortedMap(s, _) | SortedSet(s, _) => {
+                for (k, v) in s.entries.iter() {
+                    k.realize()?;
+                    v.realize()?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
+            Func(f, _) => catch_panic(|| f(args)),
             MalFunc {
                 eval,
                 ref ast,
@@ -114,15 +677,64 @@ impl MalVal {
                 ref params,
                 ..
             } => {
-                let a = &**ast;
-                let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+                let mut args = args;
+                loop {
+                    let (p, a) = fn_clause(params, ast, args.len())?;
+                    let fn_env = env_bind(Some(env.clone()), p, args, eval)?;
+                    match eval(a, fn_env) {
+                        Err(ErrRecur(new_args)) => args = new_args,
+                        res => return res,
+                    }
+                }
+            }
+            // Keywords, maps, sets and vectors can be called like functions:
+            // (:k m) and (m :k) look up a key, taking a default as an optional
+            // second argument, (s x) tests membership and (v i) indexes a vector.
+            Str(_) if self.keyword_q() => {
+                call_arity("keyword", 1, 2, &args)?;
+                Ok(args[0]
+                    .lookup(self)?
+                    .or(args.get(1).cloned())
+                    .unwrap_or(Nil))
+            }
+            Hash(..) | SortedMap(..) => {
+                call_arity("map", 1, 2, &args)?;
+                Ok(self
+                    .lookup(&args[0])?
+                    .or(args.get(1).cloned())
+                    .unwrap_or(Nil))
+            }
+            Set(..) | SortedSet(..) => {
+                call_arity("set", 1, 1, &args)?;
+                Ok(self.lookup(&args[0])?.unwrap_or(Nil))
+            }
+            Vector(ref v, _) => {
+                call_arity("vector", 1, 1, &args)?;
+                match args[0] {
+                    Int(i) if i >= 0 && (i as usize) < v.len() => Ok(v[i as usize].clone()),
+                    Int(_) => error("vector: index out of range"),
+                    _ => Err(ErrString(format!(
+                        "vector: index must be an int, got {}",
+                        args[0].pr_str(true)
+                    ))),
+                }
             }
             _ => error("attempt to call non-function"),
         }
     }
 
+    // The value for key k in a map, or k itself if it is in a set; None
+    // when it is absent or self is not a map or set.
+    pub fn lookup(&self, k: &MalVal) -> Result<Option<MalVal>, MalErr> {
+        Ok(match self {
+            Hash(hm, _) => hm.get(k).cloned(),
+            Set(hs, _) if hs.contains(k) => Some(k.clone()),
+            SortedMap(s, _) => sorted_get(s, k)?.map(|i| s.entries[i].1.clone()),
+            SortedSet(s, _) => sorted_get(s, k)?.map(|i| s.entries[i].0.clone()),
+            _ => None,
+        })
+    }
+
     pub fn keyword_q(&self) -> bool {
         match self {
             Str(s) if s.starts_with("\u{29e}") => true,
@@ -162,7 +774,13 @@ impl MalVal {
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
+            List(_, meta)
+            | Vector(_, meta)
+            | Hash(_, meta)
+            | Set(_, meta)
+            | SortedMap(_, meta)
+            | SortedSet(_, meta)
+            | LazySeq(_, meta) => Ok((&**meta).clone()),
             Func(_, meta) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
             _ => error("meta not supported by type"),
@@ -174,6 +792,10 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
+            | Set(_, ref mut meta)
+            | SortedMap(_, ref mut meta)
+            | SortedSet(_, ref mut meta)
+            | LazySeq(_, ref mut meta)
             | Func(_, ref mut meta)
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
@@ -190,51 +812,418 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
-            (List(ref a, _), List(ref b, _))
-            | (Vector(ref a, _), Vector(ref b, _))
-            | (List(ref a, _), Vector(ref b, _))
-            | (Vector(ref a, _), List(ref b, _)) => a == b,
+            (List(ref a, _), List(ref b, _)) => a == b,
+            (Vector(ref a, _), Vector(ref b, _)) => a == b,
+            (List(ref a, _), Vector(ref b, _)) | (Vector(ref b, _), List(ref a, _)) => {
+                a.len() == b.len() && a.iter().eq(b.iter())
+            }
+            (LazySeq(..), _) | (_, LazySeq(..)) => {
+                self.sequential_q() && other.sequential_q() && seq_eq(self.clone(), other.clone())
+            }
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
-            (MalFunc { .. }, MalFunc { .. }) => false,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            (Hash(..), SortedMap(..))
+            | (SortedMap(..), Hash(..))
+            | (SortedMap(..), SortedMap(..)) => self.map_entries() == other.map_entries(),
+            (Set(..), SortedSet(..))
+            | (SortedSet(..), Set(..))
+            | (SortedSet(..), SortedSet(..)) => self.set_elems() == other.set_elems(),
+            (Func(ref a, _), Func(ref b, _)) => *a as usize == *b as usize,
+            (
+                MalFunc {
+                    ast: ref a_ast,
+                    env: ref a_env,
+                    ..
+                },
+                MalFunc {
+                    ast: ref b_ast,
+                    env: ref b_env,
+                    ..
+                },
+            ) => Rc::ptr_eq(a_ast, b_ast) && Rc::ptr_eq(a_env, b_env),
+            (Atom(ref a), Atom(ref b)) => Rc::ptr_eq(a, b),
+            (Regex(ref a), Regex(ref b)) => a.as_str() == b.as_str(),
+            (Reader(ref a), Reader(ref b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 }
 
+impl Eq for MalVal {}
+
+// Consistent with eq so any value can be a hash-map key: lists, vectors
+// and lazy seqs hash alike, functions, atoms and readers hash by
+// identity, and regexes by their pattern.
+impl StdHash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => 0.hash(state),
+            Bool(b) => {
+                1.hash(state);
+                b.hash(state)
+            }
+            Int(i) => {
+                2.hash(state);
+                i.hash(state)
+            }
+            Float(f) => {
+                12.hash(state);
+                // 0.0 == -0.0, so they must hash alike
+                (if *f == 0.0 { 0.0 } else { *f }).to_bits().hash(state)
+            }
+            Str(s) => {
+                3.hash(state);
+                s.hash(state)
+            }
+            Sym(s) => {
+                4.hash(state);
+                s.hash(state)
+            }
+            List(..) | Vector(..) => {
+                5.hash(state);
+                for x in self.seq_iter().unwrap() {
+                    x.hash(state)
+                }
+            }
+            LazySeq(..) => {
+                5.hash(state);
+                for x in self.seq_vec().unwrap_or_default().iter() {
+                    x.hash(state)
+                }
+            }
+            Hash(..) | SortedMap(..) => {
+                // equal maps can list their entries in different orders,
+                // so combine entry hashes with a commutative operation
+                6.hash(state);
+                let mut sum: u64 = 0;
+                for (k, v) in self.map_entries().unwrap().iter() {
+                    let mut h = FnvHasher::default();
+                    k.hash(&mut h);
+                    v.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                sum.hash(state)
+            }
+            Set(..) | SortedSet(..) => {
+                13.hash(state);
+                let mut sum: u64 = 0;
+                for mv in self.set_elems().unwrap().iter() {
+                    let mut h = FnvHasher::default();
+                    mv.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                sum.hash(state)
+            }
+            Func(f, _) => {
+                7.hash(state);
+                (*f as usize).hash(state)
+            }
+            MalFunc { ast, env, .. } => {
+                8.hash(state);
+                (&**ast as *const MalVal).hash(state);
+                (&**env as *const EnvStruct).hash(state)
+            }
+            Atom(a) => {
+                9.hash(state);
+                (&**a as *const RefCell<MalVal>).hash(state)
+            }
+            Regex(re) => {
+                10.hash(state);
+                re.as_str().hash(state)
+            }
+            Reader(r) => {
+                11.hash(state);
+                (&**r as *const RefCell<LineReader>).hash(state)
+            }
+        }
+    }
+}
+
+// The number of required parameters in a fn* parameter list, and
+// whether it takes more after a &.
+pub fn fn_arity(params: &MalVal) -> (usize, bool) {
+    let params = params.seq_iter().map_or(vec![], |p| p.collect::<Vec<_>>());
+    match params.iter().position(|p| **p == Sym("&".to_string())) {
+        Some(i) => (i, true),
+        None => (params.len(), false),
+    }
+}
+
+// A multi-arity fn* keeps its ([params] body) clauses as its ast, with nil
+// params. The params and body to use for a call with n args: those of
+// the clause taking exactly n if there is one, else the variadic clause.
+pub fn fn_clause(params: &MalVal, ast: &MalVal, n: usize) -> Result<(MalVal, MalVal), MalErr> {
+    let clauses = match (params, ast.seq_iter()) {
+        (Nil, Some(clauses)) => clauses.collect::<Vec<_>>(),
+        _ => return Ok((params.clone(), ast.clone())),
+    };
+    let parts = |c: &MalVal| {
+        let c = c.seq_iter().unwrap().cloned().collect::<MalArgs>();
+        let body = match c.len() {
+            1 => Nil,
+            2 => c[1].clone(),
+            // several body forms run in an implicit do
+            _ => list!(MalList::from(c[1..].to_vec()).cons(Sym("do".to_string()))),
+        };
+        (c[0].clone(), body)
+    };
+    let arities = clauses
+        .iter()
+        .map(|c| fn_arity(&parts(c).0))
+        .collect::<Vec<_>>();
+    let exact = arities.iter().position(|&a| a == (n, false));
+    let variadic = arities.iter().position(|&(min, v)| v && n >= min);
+    if let Some(i) = exact.or(variadic) {
+        return Ok(parts(clauses[i]));
+    }
+    let mut expected = arities
+        .iter()
+        .sorted_by_key(|a| (a.1, a.0))
+        .map(|&(min, variadic)| {
+            if variadic {
+                format!("at least {}", min)
+            } else {
+                min.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    let last = expected.pop().unwrap();
+    if !expected.is_empty() {
+        expected = vec![expected.join(", ")];
+    }
+    expected.push(last);
+    Err(ErrString(format!(
+        "wrong number of args ({}), expected {}",
+        n,
+        expected.join(" or ")
+    )))
+}
+
+// Ok when args has min to max elements, else an arity error for name.
+fn call_arity(name: &str, min: usize, max: usize, args: &MalArgs) -> Result<(), MalErr> {
+    if args.len() < min || args.len() > max {
+        let expected = match (min, max) {
+            (min, max) if min == max => format!("{}", min),
+            (min, max) => format!("{} to {}", min, max),
+        };
+        return Err(ErrString(format!(
+            "{}: wrong number of args ({}), expected {}",
+            name,
+            args.len(),
+            expected
+        )));
+    }
+    Ok(())
+}
+
+// core.rs builtins carry their signature as meta; see core::ns
+#[allow(dead_code)]
 pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
     Func(f, Rc::new(Nil))
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
-            }
-            _ => return error("key is not string"),
-        }
+        hm.insert(k.clone(), v.clone());
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
+        hm.remove(k);
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+pub fn hash_map(kvs: MalArgs) -> MalRet {
+    _assoc(MalMap::default(), kvs)
+}
+
+pub fn truthy(mv: &MalVal) -> bool {
+    !matches!(mv, Nil | Bool(false))
+}
+
+pub fn num(mv: &MalVal) -> f64 {
+    match mv {
+        Int(i) => *i as f64,
+        Float(f) => *f,
+        _ => 0.0,
+    }
+}
+
+// Natural ordering used by compare, sort and sorted collections.
+fn rank(mv: &MalVal) -> u8 {
+    match mv {
+        Nil => 0,
+        Bool(_) => 1,
+        Int(_) | Float(_) => 2,
+        Str(_) if mv.keyword_q() => 4,
+        Str(_) => 3,
+        Sym(_) => 5,
+        List(..) | Vector(..) | LazySeq(..) => 6,
+        Hash(..) | SortedMap(..) => 7,
+        Set(..) | SortedSet(..) => 8,
+        Regex(_) => 9,
+        Func(..) | MalFunc { .. } => 10,
+        Atom(_) => 11,
+        Reader(_) => 12,
+    }
+}
+
+fn identity(mv: &MalVal) -> (usize, usize) {
+    match mv {
+        Func(f, _) => (*f as usize, 0),
+        MalFunc { ast, env, .. } => (
+            &**ast as *const MalVal as usize,
+            &**env as *const _ as usize,
+        ),
+        Atom(a) => (&**a as *const _ as usize, 0),
+        Reader(r) => (&**r as *const _ as usize, 0),
+        _ => (0, 0),
+    }
+}
+
+fn compare_seqs(a: &[MalVal], b: &[MalVal]) -> Result<Ordering, MalErr> {
+    for (x, y) in a.iter().zip(b.iter()) {
+        match compare(x, y)? {
+            Ordering::Equal => (),
+            ord => return Ok(ord),
+        }
+    }
+    Ok(a.len().cmp(&b.len()))
+}
+
+// The elements of a map (as [k v] entries) or set, in compare order.
+fn sorted_elems(mv: &MalVal) -> Result<MalArgs, MalErr> {
+    let items = match mv.map_entries() {
+        Some(hm) => hm
+            .iter()
+            .map(|(k, v)| vector![vec![k.clone(), v.clone()]])
+            .collect(),
+        None => mv.set_elems().unwrap_or_default().into_iter().collect(),
+    };
+    merge_sort(items, &compare)
+}
+
+// A total order over all values, consistent with =. Values of different
+// kinds order nil, booleans, numbers, strings, keywords, symbols,
+// sequences, maps, sets, regexes, functions, atoms, then readers. Ints
+// and floats compare by value, with an int before an equal float and NaN
+// last. Sequences compare lexicographically, maps and sets by size and
+// then by their sorted elements, and functions, atoms and readers by
+// identity.
+pub fn compare(a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    let ord = rank(a).cmp(&rank(b));
+    if ord != Ordering::Equal {
+        return Ok(ord);
+    }
+    let is_float = |mv: &MalVal| matches!(mv, Float(_));
+    Ok(match (a, b) {
+        (Bool(x), Bool(y)) => x.cmp(y),
+        (Int(x), Int(y)) => x.cmp(y),
+        (Int(_), _) | (Float(_), _) => {
+            let (x, y) = (num(a), num(b));
+            x.partial_cmp(&y)
+                .unwrap_or_else(|| x.is_nan().cmp(&y.is_nan()))
+                .then(is_float(a).cmp(&is_float(b)))
+        }
+        (Str(x), Str(y)) | (Sym(x), Sym(y)) => x.cmp(y),
+        (Regex(x), Regex(y)) => x.as_str().cmp(y.as_str()),
+        _ if a.sequential_q() => compare_seqs(&a.seq_vec()?, &b.seq_vec()?)?,
+        _ if rank(a) == 7 || rank(a) == 8 => {
+            let (x, y) = (sorted_elems(a)?, sorted_elems(b)?);
+            match x.len().cmp(&y.len()) {
+                Ordering::Equal => compare_seqs(&x, &y)?,
+                ord => ord,
             }
-            _ => return error("key is not string"),
+        }
+        _ => identity(a).cmp(&identity(b)),
+    })
+}
+
+// A comparator returns a negative, zero or positive int, or is a
+// predicate like < that is true when its first argument comes first.
+pub fn apply_comparator(f: &MalVal, a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    match f.apply(vec![a.clone(), b.clone()])? {
+        Int(n) => Ok(n.cmp(&0)),
+        Bool(true) => Ok(Ordering::Less),
+        Bool(false) | Nil if truthy(&f.apply(vec![b.clone(), a.clone()])?) => Ok(Ordering::Greater),
+        Bool(false) | Nil => Ok(Ordering::Equal),
+        res => Err(ErrString(format!(
+            "comparator must return an int or boolean, got {}",
+            res.pr_str(true)
+        ))),
+    }
+}
+
+// A stable merge sort stopping at the first error from cmp. slice::sort_by
+// can't report errors, and may panic on an inconsistent user comparator.
+pub fn merge_sort<T, F>(mut items: Vec<T>, cmp: &F) -> Result<Vec<T>, MalErr>
+where
+    F: Fn(&T, &T) -> Result<Ordering, MalErr>,
+{
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let mut left = merge_sort(items, cmp)?.into_iter().peekable();
+    let mut right = merge_sort(right, cmp)?.into_iter().peekable();
+    let mut res = vec![];
+    loop {
+        let from_left = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) => cmp(r, l)? != Ordering::Less,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return Ok(res),
+        };
+        res.push(if from_left { left.next() } else { right.next() }.unwrap());
+    }
+}
+
+// Binary search over the entries of a sorted map or set.
+
+pub fn sorted_cmp(s: &Sorted, a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    match *s.cmp {
+        Nil => compare(a, b),
+        ref f => apply_comparator(f, a, b),
+    }
+}
+
+// The index of the first entry for which pred holds, or the number of
+// entries if there is none. pred must be false for a prefix of the
+// entries and true for the rest.
+pub fn sorted_search<F>(s: &Sorted, pred: F) -> Result<usize, MalErr>
+where
+    F: Fn(&MalVal) -> Result<bool, MalErr>,
+{
+    let (mut lo, mut hi) = (0, s.entries.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if pred(&s.entries[mid].0)? {
+            hi = mid;
+        } else {
+            lo = mid + 1;
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(lo)
 }
 
-pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+// The index of the entry for k, or Err with the index to insert it at.
+pub fn sorted_find(s: &Sorted, k: &MalVal) -> Result<Result<usize, usize>, MalErr> {
+    let i = sorted_search(s, |e| Ok(sorted_cmp(s, e, k)? != Ordering::Less))?;
+    match s.entries.get(i) {
+        Some(e) if sorted_cmp(s, &e.0, k)? == Ordering::Equal => Ok(Ok(i)),
+        _ => Ok(Err(i)),
+    }
+}
+
+pub fn sorted_get(s: &Sorted, k: &MalVal) -> Result<Option<usize>, MalErr> {
+    Ok(sorted_find(s, k)?.ok())
 }